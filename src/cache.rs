@@ -0,0 +1,533 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{Result, SpeedyError};
+
+pub const CACHE_PATH: &str = ".speedy-cache";
+/// Lists every file in the output directory that speedy generated, one
+/// path per line relative to the directory. Unlike the cache, it travels
+/// with the output, so stale files are still found after e.g. a fresh
+/// clone of a site whose output is checked in.
+pub const MANIFEST_NAME: &str = ".speedy-manifest";
+
+/// Hash some content, returning a hex digest
+pub fn hash<C: AsRef<[u8]>>(content: C) -> String {
+    format!("{:x}", Sha256::digest(content.as_ref()))
+}
+
+/// Hash several pieces of content together into a single digest
+pub fn hash_all<I, C>(contents: I) -> String
+where
+    I: IntoIterator<Item = C>,
+    C: AsRef<[u8]>,
+{
+    let mut hasher = Sha256::new();
+    for content in contents {
+        let content = content.as_ref();
+        // Include each length so that e.g. ["ab", "c"] and ["a", "bc"]
+        // don't hash the same
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(content);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Hash of the running executable, so that a new version of speedy, or
+/// one built with changes to how pages are rendered, rebuilds everything.
+/// If the executable can't be read, only its version is used.
+pub fn exe_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        env::current_exe()
+            .and_then(fs::read)
+            .map(hash)
+            .unwrap_or_else(|_| env!("CARGO_PKG_VERSION").to_owned())
+    })
+}
+
+/// What we know about a generated file from the last build
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct OutputEntry {
+    /// Hash of everything the file was rendered from
+    inputs: String,
+    /// Hash of the file itself
+    contents: String,
+}
+
+/// The on-disk form of the cache
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct CacheData {
+    /// Hashes of source files (posts, templates, and config)
+    sources: BTreeMap<String, String>,
+    outputs: BTreeMap<String, OutputEntry>,
+}
+
+/// How a generated file was affected by a build
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    Created,
+    Updated,
+    Unchanged,
+    /// The file no longer corresponds to anything we generate
+    Deleted,
+}
+
+/// Content hashes from the previous build, used to skip rendering and
+/// writing any output whose inputs haven't changed. Every file written
+/// during the build goes through here, so this is also what knows which
+/// files in the output directory are stale.
+///
+/// In a dry run, nothing is written or deleted, but changes are still
+/// reported.
+pub struct BuildCache {
+    path: PathBuf,
+    dry_run: bool,
+    previous: CacheData,
+    current: Mutex<CacheData>,
+    changes: Mutex<Vec<(String, Change)>>,
+    /// In a dry run, the contents of the files that would have been
+    /// written, so that they can still be read back
+    unwritten: Mutex<HashMap<String, Vec<u8>>>,
+}
+impl BuildCache {
+    /// Load the cache from the last build. A missing or unreadable cache
+    /// just means that everything is rebuilt.
+    pub fn load<P: AsRef<Path>>(path: P, dry_run: bool) -> Self {
+        let previous = fs::read_to_string(path.as_ref())
+            .ok()
+            .and_then(|text| serde_json::from_str(&text).ok())
+            .unwrap_or_default();
+        Self {
+            path: path.as_ref().to_owned(),
+            dry_run,
+            previous,
+            current: Mutex::new(CacheData::default()),
+            changes: Mutex::new(Vec::new()),
+            unwritten: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the hash of a source file
+    pub fn add_source<S: Into<String>>(&self, path: S, hash: String) {
+        self.lock_current().sources.insert(path.into(), hash);
+    }
+
+    /// Source files that are new or have changed since the last build
+    fn changed_sources(&self) -> Vec<String> {
+        self.lock_current()
+            .sources
+            .iter()
+            .filter(|(path, hash)| self.previous.sources.get(*path) != Some(hash))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Write the output of `render` to `path`, unless `inputs` (a hash of
+    /// everything the output depends on) is the same as last time and the
+    /// file hasn't been touched since. The file is also left alone if the
+    /// newly rendered contents match what's already there.
+    pub fn write<F, C>(&self, path: &Path, inputs: &str, render: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<C>,
+        C: AsRef<[u8]>,
+    {
+        let key = path.to_string_lossy().into_owned();
+        let previous = self.previous.outputs.get(&key);
+        let on_disk = fs::read(path).ok().map(hash);
+        let untouched = previous.is_some() && previous.map(|p| &p.contents) == on_disk.as_ref();
+
+        let (contents_hash, change) = match previous {
+            Some(previous) if untouched && previous.inputs == inputs => {
+                (previous.contents.clone(), Change::Unchanged)
+            }
+            _ => {
+                let contents = render()?;
+                let contents_hash = hash(&contents);
+                let change = match on_disk {
+                    Some(ref existing) if *existing == contents_hash => Change::Unchanged,
+                    Some(_) => Change::Updated,
+                    None => Change::Created,
+                };
+                if change != Change::Unchanged {
+                    if self.dry_run {
+                        self.unwritten
+                            .lock()
+                            .expect("poisoned lock")
+                            .insert(key.clone(), contents.as_ref().to_vec());
+                    } else {
+                        if let Some(parent) = path.parent() {
+                            fs::create_dir_all(parent).map_err(|e| SpeedyError::io(parent, e))?;
+                        }
+                        fs::write(path, contents).map_err(|e| SpeedyError::io(path, e))?;
+                    }
+                }
+                (contents_hash, change)
+            }
+        };
+        self.lock_current().outputs.insert(
+            key.clone(),
+            OutputEntry {
+                inputs: inputs.to_owned(),
+                contents: contents_hash,
+            },
+        );
+        self.changes
+            .lock()
+            .expect("poisoned lock")
+            .push((key, change));
+        Ok(())
+    }
+
+    /// Every file written (or that would have been) during this build
+    pub fn outputs(&self) -> BTreeSet<String> {
+        self.lock_current().outputs.keys().cloned().collect()
+    }
+
+    /// The contents of a file written during this build, including in a
+    /// dry run, where it's only been rendered
+    pub fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let key = path.to_string_lossy();
+        match self
+            .unwritten
+            .lock()
+            .expect("poisoned lock")
+            .get(key.as_ref())
+        {
+            Some(contents) => Ok(contents.clone()),
+            None => fs::read(path).map_err(|e| SpeedyError::io(path, e)),
+        }
+    }
+
+    /// Delete every file in `dir` that an earlier build wrote and this one
+    /// didn't, e.g. the pages of posts that have since been renamed.
+    /// Files we didn't write are never touched.
+    pub fn remove_stale(&self, dir: &Path) -> Result<()> {
+        let produced = self.outputs();
+        let stale = self
+            .previous_outputs(dir)
+            .into_iter()
+            .filter(|key| !produced.contains(key))
+            .map(PathBuf::from)
+            .filter(|file| file.is_file());
+        for file in stale {
+            let key = file.to_string_lossy().into_owned();
+            if !self.dry_run {
+                fs::remove_file(&file).map_err(|e| SpeedyError::io(&file, e))?;
+            }
+            self.changes
+                .lock()
+                .expect("poisoned lock")
+                .push((key, Change::Deleted));
+        }
+        Ok(())
+    }
+
+    /// Every file in `dir` that earlier builds wrote, according to both
+    /// the cache and the manifest there
+    fn previous_outputs(&self, dir: &Path) -> BTreeSet<String> {
+        let manifest = fs::read_to_string(dir.join(MANIFEST_NAME)).unwrap_or_default();
+        // Only trust the manifest with paths that stay inside `dir`
+        let listed = manifest
+            .lines()
+            .map(Path::new)
+            .filter(|path| {
+                path.components().next().is_some()
+                    && path.components().all(|c| matches!(c, Component::Normal(_)))
+            })
+            .map(|path| dir.join(path).to_string_lossy().into_owned());
+        self.previous
+            .outputs
+            .keys()
+            .filter(|key| Path::new(key).starts_with(dir))
+            .cloned()
+            .chain(listed)
+            .filter(|key| Path::new(key) != dir.join(MANIFEST_NAME))
+            .collect()
+    }
+
+    /// Print what changed in this build
+    pub fn report(&self) {
+        for source in self.changed_sources() {
+            println!("changed: {}", source);
+        }
+        let mut changes = self.changes.lock().expect("poisoned lock").clone();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        let prefix = if self.dry_run { "would be " } else { "" };
+        let mut unchanged = 0;
+        for (path, change) in changes {
+            match change {
+                Change::Created => println!("{}created: {}", prefix, path),
+                Change::Updated => println!("{}updated: {}", prefix, path),
+                Change::Deleted => println!("{}deleted: {}", prefix, path),
+                Change::Unchanged => unchanged += 1,
+            }
+        }
+        println!("{} file(s) unchanged", unchanged);
+    }
+
+    /// Save the hashes from this build for next time, and list the files
+    /// it generated in the manifest in `dir`. After a failed build
+    /// (`complete` is false), files from earlier builds that this one
+    /// didn't get to are carried forward, so that they're still known to
+    /// be ours once they're stale.
+    pub fn save(&self, dir: &Path, complete: bool) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut current = self.lock_current();
+        let mut listed = current
+            .outputs
+            .keys()
+            .filter(|key| Path::new(key).starts_with(dir))
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        if !complete {
+            for (key, entry) in &self.previous.outputs {
+                current
+                    .outputs
+                    .entry(key.clone())
+                    .or_insert_with(|| entry.clone());
+            }
+            listed.extend(self.previous_outputs(dir));
+        }
+
+        let manifest = listed
+            .iter()
+            .filter_map(|key| Path::new(key).strip_prefix(dir).ok())
+            .map(|path| format!("{}\n", path.to_string_lossy().replace('\\', "/")))
+            .collect::<String>();
+        let manifest_path = dir.join(MANIFEST_NAME);
+        if let Some(parent) = manifest_path.parent() {
+            fs::create_dir_all(parent).map_err(|e| SpeedyError::io(parent, e))?;
+        }
+        fs::write(&manifest_path, manifest).map_err(|e| SpeedyError::io(&manifest_path, e))?;
+
+        let text = serde_json::to_string_pretty(&*current).expect("cache is always serializable");
+        fs::write(&self.path, text).map_err(|e| SpeedyError::io(&self.path, e))
+    }
+
+    fn lock_current(&self) -> std::sync::MutexGuard<'_, CacheData> {
+        self.current.lock().expect("poisoned lock")
+    }
+}
+
+/// All files under `dir`, recursively. A missing directory has no files.
+pub fn files_in_tree(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(SpeedyError::io(dir, e)),
+    };
+    for entry in entries {
+        let path = entry.map_err(|e| SpeedyError::io(dir, e))?.path();
+        if path.is_dir() {
+            files.extend(files_in_tree(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh, empty directory for a test's files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("speedy-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write `contents` to `path` through `cache`, returning whether it
+    /// had to be rendered
+    fn write(cache: &BuildCache, path: &Path, inputs: &str, contents: &str) -> bool {
+        let mut rendered = false;
+        cache
+            .write(path, inputs, || {
+                rendered = true;
+                Ok(contents)
+            })
+            .unwrap();
+        rendered
+    }
+
+    fn changes(cache: &BuildCache) -> Vec<(String, Change)> {
+        let mut changes = cache.changes.lock().unwrap().clone();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        changes
+    }
+
+    #[test]
+    fn hash_all_separates_contents() {
+        assert_ne!(hash_all(["ab", "c"]), hash_all(["a", "bc"]));
+        assert_eq!(hash_all(["a", "b"]), hash_all(vec!["a", "b"]));
+    }
+
+    #[test]
+    fn write_skips_unchanged_inputs() {
+        let dir = test_dir("unchanged");
+        let cache_path = dir.join("cache");
+        let out = dir.join("out");
+        let page = out.join("page.html");
+
+        let cache = BuildCache::load(&cache_path, false);
+        assert!(write(&cache, &page, "v1", "one"));
+        assert_eq!(changes(&cache)[0].1, Change::Created);
+        cache.save(&out, true).unwrap();
+
+        let cache = BuildCache::load(&cache_path, false);
+        assert!(!write(&cache, &page, "v1", "one"));
+        assert!(write(&cache, &page, "v2", "two"));
+        assert_eq!(fs::read_to_string(&page).unwrap(), "two");
+        cache.save(&out, true).unwrap();
+
+        // Rendering the same contents from new inputs leaves the file be
+        let cache = BuildCache::load(&cache_path, false);
+        assert!(write(&cache, &page, "v3", "two"));
+        assert_eq!(changes(&cache)[0].1, Change::Unchanged);
+        cache.save(&out, true).unwrap();
+
+        // A file edited by hand is rendered again, even with the same inputs
+        fs::write(&page, "edited").unwrap();
+        let cache = BuildCache::load(&cache_path, false);
+        assert!(write(&cache, &page, "v3", "two"));
+        assert_eq!(changes(&cache)[0].1, Change::Updated);
+        assert_eq!(fs::read_to_string(&page).unwrap(), "two");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_writes_nothing() {
+        let dir = test_dir("dry-run");
+        let cache_path = dir.join("cache");
+        let out = dir.join("out");
+        let page = out.join("page.html");
+
+        let cache = BuildCache::load(&cache_path, true);
+        assert!(write(&cache, &page, "v1", "one"));
+        assert!(!page.exists());
+        assert_eq!(cache.read(&page).unwrap(), b"one");
+        cache.save(&out, true).unwrap();
+        assert!(!cache_path.exists());
+        assert!(!out.join(MANIFEST_NAME).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_stale_only_deletes_earlier_outputs() {
+        let dir = test_dir("stale");
+        let cache_path = dir.join("cache");
+        let out = dir.join("out");
+        let kept = out.join("kept.html");
+        let renamed = out.join("renamed.html");
+        let elsewhere = dir.join("elsewhere.html");
+        let unknown = out.join("unknown.html");
+
+        let cache = BuildCache::load(&cache_path, false);
+        for page in &[&kept, &renamed, &elsewhere] {
+            write(&cache, page, "v1", "page");
+        }
+        cache.save(&out, true).unwrap();
+        fs::write(&unknown, "not ours").unwrap();
+
+        let cache = BuildCache::load(&cache_path, false);
+        write(&cache, &kept, "v1", "page");
+        cache.remove_stale(&out).unwrap();
+        assert_eq!(
+            changes(&cache),
+            vec![
+                (kept.to_string_lossy().into_owned(), Change::Unchanged),
+                (renamed.to_string_lossy().into_owned(), Change::Deleted),
+            ]
+        );
+        assert!(kept.exists());
+        assert!(!renamed.exists());
+        assert!(elsewhere.exists());
+        assert!(unknown.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_stale_uses_the_manifest_without_a_cache() {
+        let dir = test_dir("manifest");
+        let out = dir.join("out");
+        let kept = out.join("kept.html");
+        let renamed = out.join("posts/renamed.html");
+        let outside = dir.join("outside.html");
+
+        let cache = BuildCache::load(dir.join("cache"), false);
+        write(&cache, &kept, "v1", "page");
+        write(&cache, &renamed, "v1", "page");
+        cache.save(&out, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join(MANIFEST_NAME)).unwrap(),
+            "kept.html\nposts/renamed.html\n"
+        );
+        // e.g. a fresh clone, with a manifest someone has tampered with
+        fs::write(&outside, "not ours").unwrap();
+        fs::write(
+            out.join(MANIFEST_NAME),
+            "kept.html\nposts/renamed.html\n../outside.html\n",
+        )
+        .unwrap();
+
+        let cache = BuildCache::load(dir.join("other-cache"), false);
+        write(&cache, &kept, "v1", "page");
+        cache.remove_stale(&out).unwrap();
+        assert!(kept.exists());
+        assert!(!renamed.exists());
+        assert!(outside.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_builds_carry_earlier_outputs_forward() {
+        let dir = test_dir("failed");
+        let cache_path = dir.join("cache");
+        let out = dir.join("out");
+        let first = out.join("first.html");
+        let second = out.join("second.html");
+
+        let cache = BuildCache::load(&cache_path, false);
+        write(&cache, &first, "v1", "page");
+        write(&cache, &second, "v1", "page");
+        cache.save(&out, true).unwrap();
+
+        // A build that only got as far as the first page
+        let cache = BuildCache::load(&cache_path, false);
+        write(&cache, &first, "v2", "new page");
+        cache.save(&out, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join(MANIFEST_NAME)).unwrap(),
+            "first.html\nsecond.html\n"
+        );
+
+        // Once the second page is gone for good, it's still cleaned up
+        let cache = BuildCache::load(&cache_path, false);
+        assert!(!write(&cache, &first, "v2", "new page"));
+        cache.remove_stale(&out).unwrap();
+        assert!(!second.exists());
+        cache.save(&out, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join(MANIFEST_NAME)).unwrap(),
+            "first.html\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache;
+use crate::error::{strip_location, Location, Result, SpeedyError};
+
+pub const DEFAULT_CONFIG_PATH: &str = "speedy.toml";
+
+/// Top-level configuration, read from `speedy.toml`
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    pub site: SiteConfig,
+    #[serde(default)]
+    pub build: BuildConfig,
+    /// Keyed by tag name, e.g. `[tags.rust]`
+    #[serde(default)]
+    pub tags: BTreeMap<String, TagConfig>,
+    #[serde(default)]
+    pub toc: TocConfig,
+    /// Where the config was loaded from
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// Hash of the config file's contents
+    #[serde(skip)]
+    pub hash: String,
+}
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let config_txt = fs::read_to_string(path).map_err(|e| SpeedyError::io(path, e))?;
+        let mut config: Self = toml::from_str(&config_txt).map_err(|e| SpeedyError::Config {
+            path: path.to_owned(),
+            location: Location::from_toml(&e),
+            message: strip_location(&e),
+        })?;
+        if config.site.short_name.is_empty() {
+            config.site.short_name = config.site.title.clone();
+        }
+        config.path = path.to_owned();
+        config.hash = cache::hash(&config_txt);
+        Ok(config)
+    }
+
+    /// Refuse an output directory that's the working directory or holds
+    /// any of the site's sources, since stale files in it are deleted
+    pub fn check_output_dir(&self) -> Result<()> {
+        let error = |message| SpeedyError::Config {
+            path: self.path.clone(),
+            location: None,
+            message,
+        };
+        let cwd = env::current_dir().map_err(|e| SpeedyError::io(".", e))?;
+        let cwd = absolute(&cwd, ".");
+        let output = absolute(&cwd, &self.build.output_dir);
+        if cwd.starts_with(&output) {
+            return Err(error(format!(
+                "output directory {:?} contains the working directory",
+                self.build.output_dir
+            )));
+        }
+        let build = &self.build;
+        let sources = vec![
+            ("posts_dir", Some(&build.posts_dir)),
+            ("drafts_dir", Some(&build.drafts_dir)),
+            ("templates_dir", Some(&build.templates_dir)),
+            ("theme_dir", build.theme_dir.as_ref()),
+            ("assets_dir", Some(&build.assets_dir)),
+        ];
+        for (key, dir) in sources {
+            match dir {
+                Some(dir) if absolute(&cwd, dir).starts_with(&output) => {
+                    return Err(error(format!(
+                        "output directory {:?} contains {} {:?}",
+                        build.output_dir, key, dir
+                    )))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `path` relative to `cwd`, with any `.` and `..` resolved. Symlinks are
+/// followed for the part of the path that exists.
+fn absolute(cwd: &Path, path: &str) -> PathBuf {
+    let mut absolute = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            component => absolute.push(component),
+        }
+        if let Ok(canonical) = absolute.canonicalize() {
+            absolute = canonical;
+        }
+    }
+    absolute
+}
+
+/// Values describing the site itself. These are available in every
+/// template as `site`, e.g. `{{ site.author }}`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SiteConfig {
+    /// Absolute URL of the site root, without a trailing slash
+    pub url_base: String,
+    pub title: String,
+    /// A short form of the title for page titles and the header, e.g.
+    /// initials. Defaults to the title.
+    #[serde(default)]
+    pub short_name: String,
+    pub author: String,
+    pub description: String,
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    pub copyright_holder: String,
+    /// How many posts to list on the index page
+    #[serde(default = "SiteConfig::default_num_recent_posts")]
+    pub num_recent_posts: usize,
+    /// Whether the RSS feed has each post's full content, rather than
+    /// only its summary
+    #[serde(default = "SiteConfig::default_rss_full_content")]
+    pub rss_full_content: bool,
+    #[serde(default)]
+    pub robots: RobotsConfig,
+}
+impl SiteConfig {
+    fn default_num_recent_posts() -> usize {
+        10
+    }
+
+    fn default_rss_full_content() -> bool {
+        true
+    }
+}
+
+/// What `robots.txt` asks crawlers to stay out of. It always points them
+/// at the sitemap.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RobotsConfig {
+    /// Paths no crawler should visit, e.g. `/drafts/`
+    pub disallow: Vec<String>,
+    /// Crawlers to keep out of the site entirely, by user agent
+    pub blocked_agents: Vec<String>,
+}
+
+/// Optional details for a tag's page
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct TagConfig {
+    /// Defaults to the tag itself
+    pub title: Option<String>,
+    /// Markdown shown above the tag's posts
+    pub description: Option<String>,
+}
+
+/// Which headings go into a post's table of contents
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct TocConfig {
+    /// Whether posts have a table of contents unless their front matter
+    /// says otherwise with `toc: true` or `toc: false`
+    pub enabled: bool,
+    /// The highest level of heading to include, e.g. 2 for `<h2>`
+    pub min_depth: u32,
+    /// The lowest level of heading to include
+    pub max_depth: u32,
+}
+impl Default for TocConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_depth: 2,
+            max_depth: 3,
+        }
+    }
+}
+
+/// Options controlling what goes into a build. Most of these may also
+/// be overridden from the command line.
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct BuildConfig {
+    /// Where generated files are written. Files that an earlier build
+    /// wrote here and this one didn't are deleted.
+    pub output_dir: String,
+    /// Hand-maintained files (CSS, fonts, etc.), copied as-is into the
+    /// output directory
+    pub assets_dir: String,
+    pub templates_dir: String,
+    /// Templates here take precedence over those in `templates_dir`.
+    /// Any not found in either place fall back to the built-in defaults.
+    pub theme_dir: Option<String>,
+    pub posts_dir: String,
+    /// Posts here are always drafts, regardless of their front matter
+    pub drafts_dir: String,
+    /// Whether to include drafts in the build
+    pub drafts: bool,
+    /// How many threads to render with. Zero means one per CPU.
+    pub jobs: usize,
+    /// The name of one of syntect's built-in themes, or the path to a
+    /// `.tmTheme` file, used to color code blocks
+    pub highlight_theme: String,
+}
+impl Default for BuildConfig {
+    fn default() -> Self {
+        Self {
+            output_dir: "static".into(),
+            assets_dir: "assets".into(),
+            templates_dir: "templates".into(),
+            theme_dir: None,
+            posts_dir: "posts".into(),
+            drafts_dir: "in-progress".into(),
+            drafts: false,
+            jobs: 0,
+            highlight_theme: "base16-ocean.dark".into(),
+        }
+    }
+}
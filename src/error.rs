@@ -0,0 +1,269 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub type Result<T> = std::result::Result<T, SpeedyError>;
+
+/// A 1-indexed position within a source file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+impl Location {
+    pub fn new(line: usize, column: usize) -> Self {
+        Self { line, column }
+    }
+
+    /// toml reports 0-indexed positions
+    pub fn from_toml(error: &toml::de::Error) -> Option<Self> {
+        error
+            .line_col()
+            .map(|(line, col)| Self::new(line + 1, col + 1))
+    }
+
+    /// Shift a location found within a snippet of a file (e.g. its front
+    /// matter) to where that snippet starts in the file
+    pub fn offset_lines(self, lines: usize) -> Self {
+        Self {
+            line: self.line + lines,
+            column: self.column,
+        }
+    }
+}
+
+/// Everything that can go wrong while building the site
+#[derive(Debug)]
+pub enum SpeedyError {
+    /// Reading or writing a file failed
+    Io { path: PathBuf, source: io::Error },
+    /// The site configuration couldn't be parsed
+    Config {
+        path: PathBuf,
+        location: Option<Location>,
+        message: String,
+    },
+    /// A post's front matter was missing or malformed
+    FrontMatter {
+        path: PathBuf,
+        location: Option<Location>,
+        message: String,
+    },
+    /// A template couldn't be parsed
+    TemplateParse {
+        name: String,
+        location: Option<Location>,
+        message: String,
+    },
+    /// A template couldn't be rendered
+    TemplateRender { name: String, message: String },
+    /// A generated feed wasn't well-formed, or broke the rules of its
+    /// format
+    Feed {
+        path: PathBuf,
+        location: Option<Location>,
+        message: String,
+    },
+    /// A post links to a page or an anchor that doesn't exist
+    BrokenLink {
+        path: PathBuf,
+        location: Option<Location>,
+        message: String,
+    },
+    /// Watching for changes failed
+    Watch { path: PathBuf, message: String },
+}
+impl SpeedyError {
+    pub fn io<P: AsRef<Path>>(path: P, source: io::Error) -> Self {
+        SpeedyError::Io {
+            path: path.as_ref().to_owned(),
+            source,
+        }
+    }
+
+    pub fn template_parse<S: Into<String>>(name: S, error: liquid::Error) -> Self {
+        let message = error.to_string();
+        SpeedyError::TemplateParse {
+            name: name.into(),
+            location: liquid_location(&message),
+            message: liquid_message(&message),
+        }
+    }
+
+    pub fn template_render<S: Into<String>>(name: S, error: liquid::Error) -> Self {
+        SpeedyError::TemplateRender {
+            name: name.into(),
+            message: liquid_message(&error.to_string()),
+        }
+    }
+
+    /// Shift the location of an error found within a snippet of a file
+    /// to where that snippet starts in the file
+    pub fn offset_lines(mut self, lines: usize) -> Self {
+        match self {
+            SpeedyError::Config {
+                ref mut location, ..
+            }
+            | SpeedyError::FrontMatter {
+                ref mut location, ..
+            }
+            | SpeedyError::TemplateParse {
+                ref mut location, ..
+            } => *location = location.map(|l| l.offset_lines(lines)),
+            _ => (),
+        }
+        self
+    }
+
+    fn message(&self) -> String {
+        match self {
+            SpeedyError::Io { source, .. } => source.to_string(),
+            SpeedyError::Config { message, .. } => format!("invalid config: {}", message),
+            SpeedyError::FrontMatter { message, .. } => {
+                format!("invalid front matter: {}", message)
+            }
+            SpeedyError::TemplateParse { message, .. } => {
+                format!("couldn't parse template: {}", message)
+            }
+            SpeedyError::TemplateRender { message, .. } => {
+                format!("couldn't render template: {}", message)
+            }
+            SpeedyError::Feed { message, .. } => format!("invalid feed: {}", message),
+            SpeedyError::BrokenLink { message, .. } => format!("broken link: {}", message),
+            SpeedyError::Watch { message, .. } => {
+                format!("couldn't watch for changes: {}", message)
+            }
+        }
+    }
+
+    fn source_name(&self) -> String {
+        match self {
+            SpeedyError::Io { path, .. }
+            | SpeedyError::Config { path, .. }
+            | SpeedyError::FrontMatter { path, .. }
+            | SpeedyError::Feed { path, .. }
+            | SpeedyError::BrokenLink { path, .. }
+            | SpeedyError::Watch { path, .. } => path.display().to_string(),
+            SpeedyError::TemplateParse { name, .. } | SpeedyError::TemplateRender { name, .. } => {
+                name.clone()
+            }
+        }
+    }
+
+    fn location(&self) -> Option<Location> {
+        match self {
+            SpeedyError::Config { location, .. }
+            | SpeedyError::FrontMatter { location, .. }
+            | SpeedyError::TemplateParse { location, .. }
+            | SpeedyError::Feed { location, .. }
+            | SpeedyError::BrokenLink { location, .. } => *location,
+            _ => None,
+        }
+    }
+}
+impl SpeedyError {
+    /// Display the error in roughly the same style as rustc, as an error
+    /// or a warning
+    fn fmt_as(&self, level: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", level, self.message())?;
+        match self.location() {
+            Some(loc) => write!(
+                f,
+                "  --> {}:{}:{}",
+                self.source_name(),
+                loc.line,
+                loc.column
+            ),
+            None => write!(f, "  --> {}", self.source_name()),
+        }
+    }
+}
+impl fmt::Display for SpeedyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_as("error", f)
+    }
+}
+
+/// Displays an error as a warning, for problems that shouldn't stop a
+/// build, like broken links in drafts
+struct Warning<'a>(&'a SpeedyError);
+impl fmt::Display for Warning<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt_as("warning", f)
+    }
+}
+impl Error for SpeedyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SpeedyError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Print all errors to stderr, followed by a summary line
+pub fn report(errors: &[SpeedyError]) {
+    for error in errors {
+        eprintln!("{}\n", error);
+    }
+    eprintln!(
+        "error: aborting due to {} previous error{}",
+        errors.len(),
+        if errors.len() == 1 { "" } else { "s" }
+    );
+}
+
+/// Print problems that aren't fatal to stderr as warnings
+pub fn warn(warnings: &[SpeedyError]) {
+    for warning in warnings {
+        eprintln!("{}\n", Warning(warning));
+    }
+}
+
+/// serde_yaml and toml both append ` at line X column Y` to their
+/// messages, which we report separately
+pub fn strip_location<E: ToString>(error: &E) -> String {
+    let message = error.to_string();
+    match message.rfind(" at line ") {
+        Some(i) => message[..i].to_owned(),
+        None => message,
+    }
+}
+
+/// Liquid parse errors embed their position as ` --> line:column`
+fn liquid_location(message: &str) -> Option<Location> {
+    let position = message.split("--> ").nth(1)?.lines().next()?;
+    let mut parts = position.trim().splitn(2, ':');
+    let line = parts.next()?.parse().ok()?;
+    let column = parts.next()?.parse().ok()?;
+    Some(Location::new(line, column))
+}
+
+/// Strip liquid's own prefix and position markers from an error message,
+/// leaving the description of what went wrong
+fn liquid_message(message: &str) -> String {
+    let message = message.trim_start_matches("liquid:").trim();
+    if message.starts_with("-->") {
+        // pest-style parse errors put the description at the end, after
+        // a snippet of the template
+        message
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix("= "))
+            .collect::<Vec<&str>>()
+            .join(" ")
+    } else {
+        // other errors are a description followed by some `key=value` context
+        let mut lines = message
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && *l != "with:");
+        let description = lines.next().unwrap_or_default();
+        let context = lines.collect::<Vec<&str>>();
+        if context.is_empty() {
+            description.to_owned()
+        } else {
+            format!("{} ({})", description, context.join(", "))
+        }
+    }
+}
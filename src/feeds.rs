@@ -0,0 +1,598 @@
+//! Checks on generated feeds, so that a change to a template or a post
+//! can't quietly break them for subscribers
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use chrono::DateTime;
+use roxmltree::{Document, Node};
+
+use crate::error::{strip_location, Location, Result, SpeedyError};
+use crate::filters;
+
+const JSON_FEED_VERSION: &str = "https://jsonfeed.org/version/1.1";
+const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+
+/// A rule that an element broke
+type Check<'a, 'input> = std::result::Result<(), (Node<'a, 'input>, String)>;
+
+/// Check a generated file that may be a feed. XML and JSON files must be
+/// well-formed, Atom feeds must follow the rules of RFC 4287, and RSS and
+/// JSON feeds must have the elements or fields that their specs require.
+pub fn check(path: &Path, contents: &str) -> Result<()> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("xml") => check_xml(path, contents),
+        Some("json") => check_json(path, contents),
+        _ => Ok(()),
+    }
+}
+
+fn check_xml(path: &Path, contents: &str) -> Result<()> {
+    let doc = Document::parse(contents).map_err(|e| SpeedyError::Feed {
+        path: path.to_owned(),
+        location: Some(Location::new(e.pos().row as usize, e.pos().col as usize)),
+        message: xml_message(&e),
+    })?;
+    let root = doc.root_element();
+    let checked = if root.has_tag_name((ATOM_NS, "feed")) {
+        check_feed(root)
+    } else if root.has_tag_name("rss") {
+        check_rss(root)
+    } else {
+        Ok(())
+    };
+    checked.map_err(|(node, message)| {
+        let pos = doc.text_pos_at(node.range().start);
+        SpeedyError::Feed {
+            path: path.to_owned(),
+            location: Some(Location::new(pos.row as usize, pos.col as usize)),
+            message,
+        }
+    })
+}
+
+fn check_rss<'a, 'input>(rss: Node<'a, 'input>) -> Check<'a, 'input> {
+    if rss.attribute("version") != Some("2.0") {
+        return Err((rss, "<rss> must have version=\"2.0\"".into()));
+    }
+    let mut channels = rss_children(rss, "channel");
+    let channel = match (channels.next(), channels.next()) {
+        (Some(channel), None) => channel,
+        _ => return Err((rss, "<rss> must have exactly one <channel>".into())),
+    };
+    for &name in &["title", "link", "description"] {
+        if rss_children(channel, name).next().is_none() {
+            return Err((channel, format!("<channel> is missing <{}>", name)));
+        }
+    }
+    for date in rss_children(channel, "lastBuildDate").chain(rss_children(channel, "pubDate")) {
+        check_rfc822(date)?;
+    }
+    for item in rss_children(channel, "item") {
+        let has_text = rss_children(item, "title")
+            .chain(rss_children(item, "description"))
+            .next()
+            .is_some();
+        if !has_text {
+            return Err((item, "<item> must have a <title> or a <description>".into()));
+        }
+        for date in rss_children(item, "pubDate") {
+            check_rfc822(date)?;
+        }
+        for guid in rss_children(item, "guid") {
+            let is_permalink = guid.attribute("isPermaLink").unwrap_or("true") == "true";
+            let text = guid.text().unwrap_or_default().trim();
+            if is_permalink && !(text.starts_with("http://") || text.starts_with("https://")) {
+                return Err((
+                    guid,
+                    format!("permalink <guid> {:?} isn't an absolute URL", text),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check_rfc822<'a, 'input>(date: Node<'a, 'input>) -> Check<'a, 'input> {
+    let text = date.text().unwrap_or_default();
+    DateTime::parse_from_rfc2822(text).map(|_| ()).map_err(|e| {
+        (
+            date,
+            format!(
+                "<{}> {:?} isn't an RFC 822 date: {}",
+                date.tag_name().name(),
+                text,
+                e
+            ),
+        )
+    })
+}
+
+fn check_json(path: &Path, contents: &str) -> Result<()> {
+    let error = |location, message| SpeedyError::Feed {
+        path: path.to_owned(),
+        location,
+        message,
+    };
+    let feed = serde_json::from_str::<serde_json::Value>(contents).map_err(|e| {
+        error(
+            Some(Location::new(e.line(), e.column())),
+            strip_location(&e),
+        )
+    })?;
+    if feed["version"] != JSON_FEED_VERSION {
+        return Ok(());
+    }
+    check_json_feed(&feed).map_err(|message| error(None, message))
+}
+
+fn check_json_feed(feed: &serde_json::Value) -> std::result::Result<(), String> {
+    if !feed["title"].is_string() {
+        return Err("feed is missing its title".into());
+    }
+    let items = feed["items"]
+        .as_array()
+        .ok_or_else(|| "feed is missing its items".to_owned())?;
+    let mut ids = HashSet::new();
+    for (i, item) in items.iter().enumerate() {
+        let id = item["id"]
+            .as_str()
+            .ok_or_else(|| format!("item {} is missing its id", i))?;
+        if !ids.insert(id) {
+            return Err(format!(
+                "item {} has the same id as an earlier item: {:?}",
+                i, id
+            ));
+        }
+        if !item["content_html"].is_string() && !item["content_text"].is_string() {
+            return Err(format!(
+                "item {:?} has neither content_html nor content_text",
+                id
+            ));
+        }
+        for key in &["date_published", "date_modified"] {
+            if let Some(date) = item.get(key) {
+                let text = date.as_str().unwrap_or_default();
+                DateTime::parse_from_rfc3339(text).map_err(|e| {
+                    format!("item {:?} has an invalid {}: {:?}: {}", id, key, text, e)
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// roxmltree appends ` at <line>:<column>` to its messages, which we
+/// report separately
+fn xml_message(error: &roxmltree::Error) -> String {
+    let message = error.to_string();
+    match message.rfind(" at ") {
+        Some(i) => message[..i].to_owned(),
+        None => message,
+    }
+}
+
+fn check_feed<'a, 'input>(feed: Node<'a, 'input>) -> Check<'a, 'input> {
+    for &name in &["id", "title", "updated"] {
+        exactly_one(feed, name)?;
+    }
+    for &name in &["generator", "icon", "logo", "rights", "subtitle"] {
+        at_most_one(feed, name)?;
+    }
+    check_children(feed)?;
+    let has_author = children(feed, "author").next().is_some();
+    for entry in children(feed, "entry") {
+        check_entry(entry, has_author)?;
+    }
+    Ok(())
+}
+
+fn check_entry<'a, 'input>(entry: Node<'a, 'input>, feed_has_author: bool) -> Check<'a, 'input> {
+    for &name in &["id", "title", "updated"] {
+        exactly_one(entry, name)?;
+    }
+    for &name in &["content", "published", "rights", "source", "summary"] {
+        at_most_one(entry, name)?;
+    }
+    if !feed_has_author && children(entry, "author").next().is_none() {
+        return Err((
+            entry,
+            "entries must have an author when their feed doesn't".into(),
+        ));
+    }
+    let has_alternate = children(entry, "link").any(|link| link_rel(link) == "alternate");
+    if children(entry, "content").next().is_none() && !has_alternate {
+        return Err((
+            entry,
+            "entries without content must have an alternate link".into(),
+        ));
+    }
+    check_children(entry)
+}
+
+/// Check the elements that may appear in both feeds and entries
+fn check_children<'a, 'input>(parent: Node<'a, 'input>) -> Check<'a, 'input> {
+    let mut alternates = HashSet::new();
+    for child in parent.children().filter(|c| is_atom(*c)) {
+        match child.tag_name().name() {
+            "id" => check_id(child)?,
+            "published" | "updated" => check_date(child)?,
+            "rights" | "subtitle" | "summary" | "title" => check_text(child)?,
+            "content" => check_content(child)?,
+            "author" | "contributor" => exactly_one(child, "name")?,
+            "category" => require_attribute(child, "term")?,
+            "link" => {
+                require_attribute(child, "href")?;
+                let key = (child.attribute("type"), child.attribute("hreflang"));
+                if link_rel(child) == "alternate" && !alternates.insert(key) {
+                    return Err((
+                        child,
+                        "only one alternate link is allowed per type and language".into(),
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn check_id<'a, 'input>(id: Node<'a, 'input>) -> Check<'a, 'input> {
+    let text = id.text().unwrap_or_default().trim();
+    if filters::has_scheme(text) {
+        Ok(())
+    } else {
+        Err((id, format!("id {:?} isn't an absolute IRI", text)))
+    }
+}
+
+fn check_date<'a, 'input>(date: Node<'a, 'input>) -> Check<'a, 'input> {
+    let text = date.text().unwrap_or_default();
+    DateTime::parse_from_rfc3339(text).map(|_| ()).map_err(|e| {
+        (
+            date,
+            format!(
+                "<{}> {:?} isn't an RFC 3339 date: {}",
+                date.tag_name().name(),
+                text,
+                e
+            ),
+        )
+    })
+}
+
+/// Text constructs hold plain text, escaped HTML, or a single XHTML div
+fn check_text<'a, 'input>(text: Node<'a, 'input>) -> Check<'a, 'input> {
+    let kind = text.attribute("type").unwrap_or("text");
+    match kind {
+        "text" | "html" => no_elements(text, kind),
+        "xhtml" => xhtml_div(text),
+        _ => Err((
+            text,
+            format!(
+                "<{}> has unknown type {:?}; expected text, html, or xhtml",
+                text.tag_name().name(),
+                kind
+            ),
+        )),
+    }
+}
+
+/// Content is like other text, but may also have a MIME type, or link to
+/// content elsewhere
+fn check_content<'a, 'input>(content: Node<'a, 'input>) -> Check<'a, 'input> {
+    if content.attribute("src").is_some() {
+        if content.has_children() {
+            return Err((content, "<content> with a src must be empty".into()));
+        }
+        return Ok(());
+    }
+    match content.attribute("type").unwrap_or("text") {
+        "text" | "html" | "xhtml" => check_text(content),
+        kind if kind.contains('/') => Ok(()),
+        kind => Err((
+            content,
+            format!(
+                "<content> has unknown type {:?}; expected text, html, xhtml, or a MIME type",
+                kind
+            ),
+        )),
+    }
+}
+
+fn no_elements<'a, 'input>(text: Node<'a, 'input>, kind: &str) -> Check<'a, 'input> {
+    match text.children().find(|c| c.is_element()) {
+        Some(child) => Err((
+            child,
+            format!(
+                "<{}> of type {} can't contain elements; is its content escaped?",
+                text.tag_name().name(),
+                kind
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn xhtml_div<'a, 'input>(text: Node<'a, 'input>) -> Check<'a, 'input> {
+    let mut elements = text.children().filter(|c| c.is_element());
+    match (elements.next(), elements.next()) {
+        (Some(div), None) if div.has_tag_name((XHTML_NS, "div")) => Ok(()),
+        _ => Err((
+            text,
+            format!(
+                "<{}> of type xhtml must contain a single XHTML <div>",
+                text.tag_name().name()
+            ),
+        )),
+    }
+}
+
+fn exactly_one<'a, 'input>(parent: Node<'a, 'input>, name: &'static str) -> Check<'a, 'input> {
+    match children(parent, name).count() {
+        1 => Ok(()),
+        0 => Err((
+            parent,
+            format!("<{}> is missing <{}>", parent.tag_name().name(), name),
+        )),
+        _ => Err((
+            parent,
+            format!(
+                "<{}> has more than one <{}>",
+                parent.tag_name().name(),
+                name
+            ),
+        )),
+    }
+}
+
+fn at_most_one<'a, 'input>(parent: Node<'a, 'input>, name: &'static str) -> Check<'a, 'input> {
+    match children(parent, name).nth(1) {
+        Some(extra) => Err((
+            extra,
+            format!(
+                "<{}> has more than one <{}>",
+                parent.tag_name().name(),
+                name
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+fn require_attribute<'a, 'input>(element: Node<'a, 'input>, name: &str) -> Check<'a, 'input> {
+    match element.attribute(name) {
+        Some(_) => Ok(()),
+        None => Err((
+            element,
+            format!(
+                "<{}> is missing its {} attribute",
+                element.tag_name().name(),
+                name
+            ),
+        )),
+    }
+}
+
+/// Links are alternates unless they say otherwise
+fn link_rel<'a>(link: Node<'a, '_>) -> &'a str {
+    link.attribute("rel").unwrap_or("alternate")
+}
+
+fn is_atom(node: Node) -> bool {
+    node.is_element() && node.tag_name().namespace() == Some(ATOM_NS)
+}
+
+/// Atom child elements of `parent` with the given name
+fn children<'a, 'input: 'a>(
+    parent: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    parent
+        .children()
+        .filter(move |c| is_atom(*c) && c.tag_name().name() == name)
+}
+
+/// Child elements of `parent` without a namespace, as RSS's are
+fn rss_children<'a, 'input: 'a>(
+    parent: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    parent.children().filter(move |c| {
+        c.is_element() && c.has_tag_name(name) && c.tag_name().namespace().is_none()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: &str = r#"<entry>
+        <title>Why Blog</title>
+        <link href="https://example.com/posts/why.html"/>
+        <id>https://example.com/posts/why.html</id>
+        <updated>2020-09-07T00:00:00+00:00</updated>
+        <content type="html">&lt;p&gt;Because&lt;/p&gt;</content>
+    </entry>"#;
+
+    fn atom(feed_children: &str, entry: &str) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+    {}
+    {}
+</feed>"#,
+            feed_children, entry
+        )
+    }
+
+    const FEED: &str = r#"<title>Blog</title>
+    <id>https://example.com/</id>
+    <updated>2020-09-07T00:00:00+00:00</updated>
+    <author><name>Someone</name></author>"#;
+
+    fn rss(channel: &str) -> String {
+        format!(
+            r#"<rss version="2.0"><channel>
+    <title>Blog</title>
+    <link>https://example.com/</link>
+    <description>A blog</description>
+    {}
+</channel></rss>"#,
+            channel
+        )
+    }
+
+    /// The message and line of the error from checking a feed
+    fn error(name: &str, contents: &str) -> (String, Option<usize>) {
+        match check(Path::new(name), contents) {
+            Err(SpeedyError::Feed {
+                message, location, ..
+            }) => (message, location.map(|l| l.line)),
+            other => panic!("expected a feed error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn valid_feeds_pass() {
+        check(Path::new("atom.xml"), &atom(FEED, ENTRY)).unwrap();
+        check(
+            Path::new("rss.xml"),
+            &rss(
+                "<lastBuildDate>Mon, 07 Sep 2020 00:00:00 +0000</lastBuildDate>\
+                  <item><title>Why</title><guid>https://example.com/why.html</guid></item>",
+            ),
+        )
+        .unwrap();
+        check(
+            Path::new("feed.json"),
+            r#"{"version": "https://jsonfeed.org/version/1.1", "title": "Blog",
+                "items": [{"id": "1", "content_text": "Hi",
+                           "date_published": "2020-09-07T00:00:00+00:00"}]}"#,
+        )
+        .unwrap();
+        // Files that aren't feeds only need to be well-formed
+        check(Path::new("sitemap.xml"), "<urlset/>").unwrap();
+        check(Path::new("search.json"), "[]").unwrap();
+        check(Path::new("index.html"), "<p>not checked").unwrap();
+    }
+
+    #[test]
+    fn malformed_files_fail() {
+        let (message, line) = error("sitemap.xml", "<urlset>\n<url>\n</urlset>");
+        assert!(message.contains("url"), "{}", message);
+        assert_eq!(line, Some(3));
+        let (_, line) = error("search.json", "[\n1,\n]");
+        assert_eq!(line, Some(3));
+    }
+
+    #[test]
+    fn atom_rules() {
+        let missing_id = atom(
+            FEED,
+            &ENTRY.replace("<id>https://example.com/posts/why.html</id>", ""),
+        );
+        assert_eq!(
+            error("atom.xml", &missing_id),
+            ("<entry> is missing <id>".to_owned(), Some(7))
+        );
+        let relative_id = atom(&FEED.replace("https://example.com/", "/"), ENTRY);
+        assert_eq!(
+            error("atom.xml", &relative_id).0,
+            "id \"/\" isn't an absolute IRI"
+        );
+        let unescaped = atom(
+            FEED,
+            &ENTRY.replace(
+                "<title>Why Blog</title>",
+                "<title>Why <em>Blog</em></title>",
+            ),
+        );
+        assert_eq!(
+            error("atom.xml", &unescaped).0,
+            "<title> of type text can't contain elements; is its content escaped?"
+        );
+        let bad_date = atom(
+            FEED,
+            &ENTRY.replace("2020-09-07T00:00:00+00:00", "2020-09-07"),
+        );
+        assert!(error("atom.xml", &bad_date)
+            .0
+            .starts_with("<updated> \"2020-09-07\" isn't an RFC 3339 date"));
+        let no_author = atom(
+            &FEED.replace("<author><name>Someone</name></author>", ""),
+            ENTRY,
+        );
+        assert_eq!(
+            error("atom.xml", &no_author).0,
+            "entries must have an author when their feed doesn't"
+        );
+    }
+
+    #[test]
+    fn rss_rules() {
+        assert_eq!(
+            error(
+                "rss.xml",
+                "<rss version=\"2.0\"><channel><title>Blog</title></channel></rss>"
+            )
+            .0,
+            "<channel> is missing <link>"
+        );
+        assert!(
+            error("rss.xml", &rss("<lastBuildDate>2020-09-07</lastBuildDate>"))
+                .0
+                .starts_with("<lastBuildDate> \"2020-09-07\" isn't an RFC 822 date")
+        );
+        assert_eq!(
+            error(
+                "rss.xml",
+                &rss("<item><link>https://example.com/</link></item>")
+            )
+            .0,
+            "<item> must have a <title> or a <description>"
+        );
+        assert_eq!(
+            error(
+                "rss.xml",
+                &rss("<item><title>Why</title><guid>/why.html</guid></item>")
+            )
+            .0,
+            "permalink <guid> \"/why.html\" isn't an absolute URL"
+        );
+        check(
+            Path::new("rss.xml"),
+            &rss("<item><title>Why</title><guid isPermaLink=\"false\">why</guid></item>"),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn json_feed_rules() {
+        let feed = |items: &str| {
+            format!(
+                r#"{{"version": "https://jsonfeed.org/version/1.1", "title": "Blog", "items": [{}]}}"#,
+                items
+            )
+        };
+        assert_eq!(
+            error(
+                "feed.json",
+                &feed(r#"{"id": "1", "content_html": "a"}, {"id": "1", "content_html": "b"}"#)
+            )
+            .0,
+            "item 1 has the same id as an earlier item: \"1\""
+        );
+        assert_eq!(
+            error("feed.json", &feed(r#"{"id": "1"}"#)).0,
+            "item \"1\" has neither content_html nor content_text"
+        );
+        assert!(error(
+            "feed.json",
+            &feed(r#"{"id": "1", "content_html": "a", "date_modified": "2020-09-07"}"#)
+        )
+        .0
+        .starts_with("item \"1\" has an invalid date_modified: \"2020-09-07\""));
+    }
+}
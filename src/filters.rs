@@ -0,0 +1,686 @@
+//! Liquid filters for formatting site data in templates, e.g.
+//! `{{ post.created | date: "%B %e, %Y" }}`
+
+use std::fmt::{self, Write};
+
+use chrono::{DateTime, FixedOffset, NaiveDate, Utc};
+use liquid::compiler::{Filter, FilterArguments, FilterParameters, ParseFilter};
+use liquid::derive::*;
+use liquid::error::{Error, Result};
+use liquid::interpreter::{Context, Expression};
+use liquid::value::Value;
+
+/// The format dates are given to templates in
+pub const DATE_FMT: &str = "%Y-%m-%d";
+/// A leisurely reading speed, since posts are often technical
+const WORDS_PER_MINUTE: usize = 200;
+
+/// Add speedy's filters to `builder`. `url_base` is prepended to paths
+/// by `absolute_url`.
+pub fn add_filters<P>(builder: liquid::ParserBuilder<P>, url_base: &str) -> liquid::ParserBuilder<P>
+where
+    P: liquid::partials::PartialCompiler,
+{
+    builder
+        .filter(Date)
+        .filter(Rfc3339)
+        .filter(Rfc822)
+        .filter(Slugify)
+        .filter(ReadingTime)
+        .filter(TagUrl)
+        .filter(AbsoluteUrl {
+            url_base: url_base.trim_end_matches('/').to_owned(),
+        })
+        .filter(AbsoluteLinks {
+            url_base: url_base.trim_end_matches('/').to_owned(),
+        })
+        .filter(XmlEscape)
+        .filter(StripHtml)
+        .filter(Json)
+}
+
+/// Lowercase `text`, replacing every run of anything other than letters
+/// and numbers with a single `-`. `+` and `#` are spelled out, so that
+/// e.g. `C++`, `C#`, and `C` don't all end up as `c`.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::with_capacity(text.len());
+    let separate = |slug: &mut String| {
+        if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    };
+    for c in text.chars().flat_map(char::to_lowercase) {
+        match c {
+            '+' | '#' => {
+                separate(&mut slug);
+                slug.push_str(if c == '+' { "plus" } else { "sharp" });
+                slug.push('-');
+            }
+            c if c.is_alphanumeric() => slug.push(c),
+            _ => separate(&mut slug),
+        }
+    }
+    let len = slug.trim_end_matches('-').len();
+    slug.truncate(len);
+    slug
+}
+
+/// The URL of the page listing the posts with the given tag
+pub fn tag_url(tag: &str) -> String {
+    format!("/tags/{}.html", slugify(tag))
+}
+
+/// Escape the characters that are special in XML (and HTML)
+pub fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The text of some HTML, without its tags and with its character
+/// references decoded, e.g. for places that only take plain text
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(i) = rest.find(['<', '&']) {
+        text.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if rest.starts_with('<') {
+            match rest.find('>') {
+                Some(end) => rest = &rest[end + 1..],
+                None => {
+                    text.push_str(rest);
+                    rest = "";
+                }
+            }
+            continue;
+        }
+        let decoded = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| decode_reference(&rest[1..end]).map(|c| (c, end)));
+        match decoded {
+            Some((c, end)) => {
+                text.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// The character a reference like `&amp;` or `&#8212;` stands for, given
+/// what's between the `&` and the `;`. Only the named references likely in
+/// prose are known.
+fn decode_reference(name: &str) -> Option<char> {
+    if let Some(number) = name.strip_prefix('#') {
+        let code = match number
+            .strip_prefix('x')
+            .or_else(|| number.strip_prefix('X'))
+        {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => number.parse(),
+        };
+        return code.ok().and_then(std::char::from_u32);
+    }
+    let c = match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "ndash" => '–',
+        "mdash" => '—',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "hellip" => '…',
+        "copy" => '©',
+        _ => return None,
+    };
+    Some(c)
+}
+
+/// Make every `href` and `src` in some HTML absolute, e.g. for feeds,
+/// which may be read far from the site. Links relative to the page
+/// (including `#fragment` links) are resolved against `page_url`.
+pub fn absolute_links(html: &str, url_base: &str, page_url: &str) -> String {
+    let page_dir = &page_url[..page_url.rfind('/').map_or(0, |i| i + 1)];
+    let mut result = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some((start, quote)) = next_link(rest) {
+        let (before, after) = rest.split_at(start);
+        result.push_str(before);
+        let end = after.find(quote).unwrap_or(after.len());
+        let link = &after[..end];
+        if has_scheme(link) || link.starts_with("//") {
+            result.push_str(link);
+        } else if link.starts_with('/') {
+            result.push_str(url_base);
+            result.push_str(link);
+        } else if link.starts_with('#') {
+            result.push_str(page_url.split('#').next().unwrap_or_default());
+            result.push_str(link);
+        } else {
+            result.push_str(page_dir);
+            result.push_str(link);
+        }
+        rest = &after[end..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Where the value of the next `href` or `src` attribute starts, along
+/// with the quote that ends it
+fn next_link(html: &str) -> Option<(usize, char)> {
+    ["href=\"", "href='", "src=\"", "src='"]
+        .iter()
+        .filter_map(|attr| {
+            html.match_indices(attr)
+                .find(|(i, _)| html[..*i].ends_with(char::is_whitespace))
+                .map(|(i, _)| (i + attr.len(), attr.chars().last().unwrap()))
+        })
+        .min_by_key(|(i, _)| *i)
+}
+
+/// Whether a link starts with a URL scheme, like `https:` or `mailto:`
+pub fn has_scheme(link: &str) -> bool {
+    match link.find(':') {
+        Some(i) => {
+            let scheme = &link[..i];
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }
+        None => false,
+    }
+}
+
+/// Minutes it takes to read some HTML or text, rounded up
+pub fn reading_time(html: &str) -> usize {
+    let mut in_tag = false;
+    let text = html
+        .chars()
+        .map(|c| match c {
+            '<' => {
+                in_tag = true;
+                ' '
+            }
+            '>' if in_tag => {
+                in_tag = false;
+                ' '
+            }
+            _ if in_tag => ' ',
+            c => c,
+        })
+        .collect::<String>();
+    let words = text.split_whitespace().count();
+    words.div_ceil(WORDS_PER_MINUTE).max(1)
+}
+
+fn invalid_format(format: &str) -> Error {
+    Error::with_msg("invalid date format").context("format", format.to_owned())
+}
+
+/// Interpret a value as a date, accepting the `YYYY-MM-DD` dates given
+/// to templates as well as anything liquid itself understands as one
+fn to_date(input: &Value) -> Result<DateTime<FixedOffset>> {
+    let error = || Error::with_msg("expected a date").context("input", input.source().to_string());
+    let scalar = input.as_scalar().ok_or_else(error)?;
+    NaiveDate::parse_from_str(scalar.to_str().trim(), DATE_FMT)
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|datetime| DateTime::<Utc>::from_naive_utc_and_offset(datetime, Utc).into())
+        .or_else(|| scalar.to_date())
+        .ok_or_else(error)
+}
+
+/// Reject any arguments given to a filter that takes none
+fn no_arguments(mut args: FilterArguments) -> Result<()> {
+    if args.positional.next().is_some() {
+        return Err(Error::with_msg("Invalid number of positional arguments")
+            .context("cause", "expected at most 0 positional arguments"));
+    }
+    if let Some(arg) = args.keyword.next() {
+        return Err(Error::with_msg(format!(
+            "Unexpected named argument `{}`",
+            arg.0
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Debug, FilterParameters)]
+struct DateArgs {
+    #[parameter(description = "The format to return the date in.", arg_type = "str")]
+    format: Expression,
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "date",
+    description = "Formats a date with a chrono format string, e.g. \"%B %e, %Y\".",
+    parameters(DateArgs),
+    parsed(DateFilter)
+)]
+struct Date;
+
+#[derive(Debug, FromFilterParameters, Display_filter)]
+#[name = "date"]
+struct DateFilter {
+    #[parameters]
+    args: DateArgs,
+}
+
+impl Filter for DateFilter {
+    fn evaluate(&self, input: &Value, context: &Context) -> Result<Value> {
+        let args = self.args.evaluate(context)?;
+        let date = to_date(input)?;
+        // `to_string` would panic on a format chrono doesn't understand
+        let mut formatted = String::new();
+        write!(formatted, "{}", date.format(args.format.as_ref()))
+            .map_err(|_| invalid_format(&args.format))?;
+        Ok(Value::scalar(formatted))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "rfc3339",
+    description = "Formats a date as RFC 3339, e.g. for feeds.",
+    parsed(Rfc3339Filter)
+)]
+struct Rfc3339;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "rfc3339"]
+struct Rfc3339Filter;
+
+impl Filter for Rfc3339Filter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        Ok(Value::scalar(to_date(input)?.to_rfc3339()))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "rfc822",
+    description = "Formats a date for RSS feeds.",
+    parsed(Rfc822Filter)
+)]
+struct Rfc822;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "rfc822"]
+struct Rfc822Filter;
+
+impl Filter for Rfc822Filter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        // Not `to_rfc2822`, which panics on dates RFC 2822 can't represent
+        let date = to_date(input)?;
+        Ok(Value::scalar(
+            date.format("%a, %d %b %Y %H:%M:%S %z").to_string(),
+        ))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "slugify",
+    description = "Makes text suitable for use in a URL or an id.",
+    parsed(SlugifyFilter)
+)]
+struct Slugify;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "slugify"]
+struct SlugifyFilter;
+
+impl Filter for SlugifyFilter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        Ok(Value::scalar(slugify(&input.to_str())))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "reading_time",
+    description = "Estimates how many minutes some HTML takes to read.",
+    parsed(ReadingTimeFilter)
+)]
+struct ReadingTime;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "reading_time"]
+struct ReadingTimeFilter;
+
+impl Filter for ReadingTimeFilter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        Ok(Value::scalar(reading_time(&input.to_str()) as i32))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "tag_url",
+    description = "Links to the posts with a tag.",
+    parsed(TagUrlFilter)
+)]
+struct TagUrl;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "tag_url"]
+struct TagUrlFilter;
+
+impl Filter for TagUrlFilter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        Ok(Value::scalar(tag_url(&input.to_str())))
+    }
+}
+
+/// Unlike the other filters, this needs to know about the site, so its
+/// parsing is implemented by hand
+#[derive(Clone, FilterReflection)]
+#[filter(
+    name = "absolute_url",
+    description = "Prepends the site's base URL to a path.",
+    parsed(AbsoluteUrlFilter)
+)]
+struct AbsoluteUrl {
+    url_base: String,
+}
+
+impl ParseFilter for AbsoluteUrl {
+    fn parse(&self, args: FilterArguments) -> Result<Box<dyn Filter>> {
+        no_arguments(args)?;
+        Ok(Box::new(AbsoluteUrlFilter {
+            url_base: self.url_base.clone(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct AbsoluteUrlFilter {
+    url_base: String,
+}
+
+impl fmt::Display for AbsoluteUrlFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "absolute_url")
+    }
+}
+
+impl Filter for AbsoluteUrlFilter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        let path = input.to_str();
+        // Leave URLs that are already absolute alone
+        if path.contains("://") {
+            return Ok(Value::scalar(path.into_owned()));
+        }
+        Ok(Value::scalar(format!(
+            "{}/{}",
+            self.url_base,
+            path.trim_start_matches('/')
+        )))
+    }
+}
+
+#[derive(Debug, FilterParameters)]
+struct AbsoluteLinksArgs {
+    #[parameter(
+        description = "The URL of the page the HTML is from, for resolving relative links.",
+        arg_type = "str"
+    )]
+    page_url: Option<Expression>,
+}
+
+/// Like `absolute_url`, this needs to know about the site
+#[derive(Clone, FilterReflection)]
+#[filter(
+    name = "absolute_links",
+    description = "Makes every link and image source in some HTML absolute.",
+    parameters(AbsoluteLinksArgs),
+    parsed(AbsoluteLinksFilter)
+)]
+struct AbsoluteLinks {
+    url_base: String,
+}
+
+impl ParseFilter for AbsoluteLinks {
+    fn parse(&self, args: FilterArguments) -> Result<Box<dyn Filter>> {
+        Ok(Box::new(AbsoluteLinksFilter {
+            args: AbsoluteLinksArgs::from_args(args)?,
+            url_base: self.url_base.clone(),
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct AbsoluteLinksFilter {
+    args: AbsoluteLinksArgs,
+    url_base: String,
+}
+
+impl fmt::Display for AbsoluteLinksFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "absolute_links: {}", self.args)
+    }
+}
+
+impl Filter for AbsoluteLinksFilter {
+    fn evaluate(&self, input: &Value, context: &Context) -> Result<Value> {
+        let args = self.args.evaluate(context)?;
+        let site_root = format!("{}/", self.url_base);
+        let page_url = args.page_url.as_deref().unwrap_or(&site_root);
+        Ok(Value::scalar(absolute_links(
+            &input.to_str(),
+            &self.url_base,
+            page_url,
+        )))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "xml_escape",
+    description = "Escapes text for use in XML, e.g. in feeds.",
+    parsed(XmlEscapeFilter)
+)]
+struct XmlEscape;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "xml_escape"]
+struct XmlEscapeFilter;
+
+impl Filter for XmlEscapeFilter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        Ok(Value::scalar(xml_escape(&input.to_str())))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "strip_html",
+    description = "Removes the tags from some HTML, leaving plain text.",
+    parsed(StripHtmlFilter)
+)]
+struct StripHtml;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "strip_html"]
+struct StripHtmlFilter;
+
+impl Filter for StripHtmlFilter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        Ok(Value::scalar(strip_html(&input.to_str())))
+    }
+}
+
+#[derive(Clone, ParseFilter, FilterReflection)]
+#[filter(
+    name = "json",
+    description = "Formats a value as JSON, e.g. for JSON feeds.",
+    parsed(JsonFilter)
+)]
+struct Json;
+
+#[derive(Debug, Default, Display_filter)]
+#[name = "json"]
+struct JsonFilter;
+
+impl Filter for JsonFilter {
+    fn evaluate(&self, input: &Value, _context: &Context) -> Result<Value> {
+        serde_json::to_string(input)
+            .map(Value::scalar)
+            .map_err(|e| Error::with_msg(format!("couldn't convert to JSON: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL_BASE: &str = "https://example.com/blog";
+
+    fn render(template: &str, input: &str) -> Result<String> {
+        let parser = add_filters(liquid::ParserBuilder::with_liquid(), URL_BASE)
+            .build()
+            .expect("failed to build parser");
+        let mut globals = liquid::value::Object::new();
+        globals.insert("input".into(), Value::scalar(input.to_owned()));
+        parser.parse(template)?.render(&globals)
+    }
+
+    #[test]
+    fn date_formats_dates() {
+        let date = |format| {
+            render(
+                &format!("{{{{ input | date: {:?} }}}}", format),
+                "2020-09-07",
+            )
+        };
+        assert_eq!(date("%B %e, %Y").unwrap(), "September  7, 2020");
+        assert_eq!(date("%Y/%m").unwrap(), "2020/09");
+        assert!(date("%Q").is_err());
+        assert!(render("{{ input | date: \"%Y\" }}", "yesterday").is_err());
+    }
+
+    #[test]
+    fn feed_dates() {
+        assert_eq!(
+            render("{{ input | rfc3339 }}", "2020-09-07").unwrap(),
+            "2020-09-07T00:00:00+00:00"
+        );
+        assert_eq!(
+            render("{{ input | rfc822 }}", "2020-09-07").unwrap(),
+            "Mon, 07 Sep 2020 00:00:00 +0000"
+        );
+    }
+
+    #[test]
+    fn absolute_url_prepends_url_base() {
+        let url = |path| render("{{ input | absolute_url }}", path).unwrap();
+        assert_eq!(
+            url("/posts/a.html"),
+            "https://example.com/blog/posts/a.html"
+        );
+        assert_eq!(url("posts/a.html"), "https://example.com/blog/posts/a.html");
+        assert_eq!(url("/"), "https://example.com/blog/");
+        assert_eq!(url("https://other.org/a"), "https://other.org/a");
+    }
+
+    #[test]
+    fn absolute_links_resolves_every_kind_of_link() {
+        let page = "https://example.com/blog/posts/a.html";
+        let links = |html| absolute_links(html, URL_BASE, page);
+        assert_eq!(
+            links(r#"<a href="b.html">"#),
+            r#"<a href="https://example.com/blog/posts/b.html">"#
+        );
+        assert_eq!(
+            links(r#"<img src='/img/c.png'>"#),
+            r#"<img src='https://example.com/blog/img/c.png'>"#
+        );
+        assert_eq!(
+            links(r##"<a href="#part-2">"##),
+            r##"<a href="https://example.com/blog/posts/a.html#part-2">"##
+        );
+        assert_eq!(
+            links(r#"<a href="//cdn.example.org/x.js">"#),
+            r#"<a href="//cdn.example.org/x.js">"#
+        );
+        assert_eq!(
+            links(r#"<a href="mailto:me@example.com">"#),
+            r#"<a href="mailto:me@example.com">"#
+        );
+        // Only attributes, not text that looks like them
+        assert_eq!(links("<code>href=\"x\"</code>"), "<code>href=\"x\"</code>");
+    }
+
+    #[test]
+    fn absolute_links_defaults_to_the_site_root() {
+        assert_eq!(
+            render(r#"{{ input | absolute_links }}"#, r#"<a href="b.html">"#).unwrap(),
+            r#"<a href="https://example.com/blog/b.html">"#
+        );
+        assert_eq!(
+            render(
+                r#"{{ input | absolute_links: "https://example.com/blog/posts/a.html" }}"#,
+                r#"<a href="b.html#x">"#
+            )
+            .unwrap(),
+            r#"<a href="https://example.com/blog/posts/b.html#x">"#
+        );
+    }
+
+    #[test]
+    fn strip_html_leaves_plain_text() {
+        assert_eq!(
+            strip_html("It&rsquo;s <em>very</em> <a href=\"/x\">fast</a> &amp; small"),
+            "It’s very fast & small"
+        );
+        assert_eq!(strip_html("&#8212;&#x2014;&mdash;"), "———");
+        assert_eq!(strip_html("AT&T &unknown; a < b"), "AT&T &unknown; a < b");
+        assert_eq!(
+            render("{{ input | strip_html | json }}", "<p>Tom &amp; Jerry</p>").unwrap(),
+            "\"Tom & Jerry\""
+        );
+    }
+
+    #[test]
+    fn xml_escape_escapes_special_characters() {
+        assert_eq!(
+            xml_escape(r#"<a href="x">Tom & Jerry's</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&#39;s&lt;/a&gt;"
+        );
+        assert_eq!(xml_escape("plain"), "plain");
+    }
+
+    #[test]
+    fn slugify_keeps_plus_and_sharp() {
+        assert_eq!(slugify("RustConf 2020!"), "rustconf-2020");
+        assert_eq!(slugify("C"), "c");
+        assert_eq!(slugify("C++"), "c-plus-plus");
+        assert_eq!(slugify("C#"), "c-sharp");
+        assert_eq!(slugify("a+b"), "a-plus-b");
+        assert_eq!(slugify("?!"), "");
+    }
+}
@@ -0,0 +1,100 @@
+//! Syntax highlighting for code blocks in posts, done when the site is
+//! generated so that readers don't need to run any JavaScript
+
+use std::fs;
+use std::io::Cursor;
+
+use pulldown_cmark::escape::escape_html;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::cache;
+use crate::config::Config;
+use crate::error::{Result, SpeedyError};
+
+/// Highlighted code is marked up with classes rather than inline styles,
+/// so that the theme is only sent once, as a stylesheet. The prefix keeps
+/// them from clashing with the site's own classes.
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+pub struct Highlighter {
+    syntaxes: SyntaxSet,
+    theme: Theme,
+    /// Hash of the theme's `.tmTheme` file, if it came from one
+    pub theme_hash: Option<String>,
+}
+impl Highlighter {
+    /// Load the syntaxes and the theme named by `build.highlight_theme`,
+    /// which may be one of syntect's built-in themes or the path to a
+    /// `.tmTheme` file
+    pub fn new(config: &Config) -> Result<Self> {
+        let name = &config.build.highlight_theme;
+        let error = |message| SpeedyError::Config {
+            path: config.path.clone(),
+            location: None,
+            message,
+        };
+        let mut theme_hash = None;
+        let theme = if name.ends_with(".tmTheme") {
+            let load_error = |e: &dyn std::fmt::Display| {
+                error(format!("couldn't load highlight theme {:?}: {}", name, e))
+            };
+            let contents = fs::read(name).map_err(|e| load_error(&e))?;
+            theme_hash = Some(cache::hash(&contents));
+            ThemeSet::load_from_reader(&mut Cursor::new(contents)).map_err(|e| load_error(&e))?
+        } else {
+            let mut themes = ThemeSet::load_defaults().themes;
+            let available = themes.keys().cloned().collect::<Vec<_>>().join(", ");
+            themes.remove(name).ok_or_else(|| {
+                error(format!(
+                    "unknown highlight theme {:?}; expected a .tmTheme file or one of: {}",
+                    name, available
+                ))
+            })?
+        };
+        Ok(Self {
+            syntaxes: SyntaxSet::load_defaults_newlines(),
+            theme,
+            theme_hash,
+        })
+    }
+
+    /// Render a code block, using the syntax for `lang` if there is one.
+    /// Blocks in unknown languages are still wrapped in the same markup,
+    /// so that they're styled like the rest.
+    pub fn code_block(&self, lang: Option<&str>, code: &str) -> String {
+        let syntax = lang
+            .and_then(|lang| self.syntaxes.find_syntax_by_token(lang))
+            .unwrap_or_else(|| self.syntaxes.find_syntax_plain_text());
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(syntax, &self.syntaxes, CLASS_STYLE);
+        let highlighted = LinesWithEndings::from(code)
+            .try_for_each(|line| generator.parse_html_for_line_which_includes_newline(line))
+            .map(|_| generator.finalize())
+            .unwrap_or_else(|_| {
+                let mut escaped = String::new();
+                escape_html(&mut escaped, code).expect("writing to a string can't fail");
+                escaped
+            });
+        let class = match lang {
+            Some(lang) => {
+                let mut escaped = String::new();
+                escape_html(&mut escaped, lang).expect("writing to a string can't fail");
+                format!(" class=\"language-{}\"", escaped)
+            }
+            None => String::new(),
+        };
+        format!(
+            "<pre class=\"hl-code\"><code{}>{}</code></pre>\n",
+            class, highlighted
+        )
+    }
+
+    /// The stylesheet for the theme's colors
+    pub fn css(&self) -> String {
+        css_for_theme_with_class_style(&self.theme, CLASS_STYLE)
+            .expect("the class style's prefix is a valid class name")
+    }
+}
@@ -0,0 +1,415 @@
+//! Checks that links between the site's pages, and the `#fragment`s
+//! they point to, actually exist
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+
+use crate::error::{Location, SpeedyError};
+use crate::filters;
+
+/// A link found in a post's markdown
+#[derive(Debug)]
+pub struct Link {
+    /// Where the link goes, as written
+    pub dest: String,
+    /// Where the link is in the post's file
+    pub location: Location,
+}
+
+/// Find every link and image in `body`, which starts at byte `offset`
+/// of `text`, the full contents of a post
+pub fn collect(text: &str, body: &str, offset: usize, opts: Options) -> Vec<Link> {
+    Parser::new_ext(body, opts)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Start(Tag::Link(_, dest, _)) | Event::Start(Tag::Image(_, dest, _)) => {
+                Some(Link {
+                    dest: dest.into_string(),
+                    location: location_at(text, offset + range.start),
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// The line and column of a byte offset within `text`
+fn location_at(text: &str, offset: usize) -> Location {
+    let before = &text[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Location::new(
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Every `href` and `src` in some HTML, e.g. a page rendered from
+/// templates, with the line and column of each
+pub fn collect_html(html: &str) -> Vec<Link> {
+    let mut links = Vec::new();
+    for attr in &[" href=\"", " src=\""] {
+        for (start, _) in html.match_indices(attr) {
+            let value_start = start + attr.len();
+            if let Some(len) = html[value_start..].find('"') {
+                links.push(Link {
+                    dest: html_unescape(&html[value_start..value_start + len]),
+                    location: location_at(html, value_start),
+                });
+            }
+        }
+    }
+    links.sort_by_key(|link| (link.location.line, link.location.column));
+    links
+}
+
+/// Undo the escaping of the characters that are special in HTML
+fn html_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Checks links against the set of generated files
+pub struct LinkChecker<'a> {
+    url_base: &'a str,
+    /// Every generated file, relative to the output directory
+    outputs: BTreeSet<String>,
+    /// The HTML of every generated page, by path
+    pages: HashMap<&'a str, &'a str>,
+}
+impl<'a> LinkChecker<'a> {
+    pub fn new(
+        url_base: &'a str,
+        outputs: BTreeSet<String>,
+        pages: HashMap<&'a str, &'a str>,
+    ) -> Self {
+        Self {
+            url_base: url_base.trim_end_matches('/'),
+            outputs,
+            pages,
+        }
+    }
+
+    /// Check the links in the generated page at `page`, other than those
+    /// in `checked`, which were already checked where they were written.
+    /// Problems are reported against `path`, the page's file.
+    pub fn check_page(&self, path: &Path, page: &str, checked: &[Link]) -> Vec<SpeedyError> {
+        let html = match self.pages.get(page) {
+            Some(html) => html,
+            None => return Vec::new(),
+        };
+        let mut checked = checked.iter().fold(HashMap::new(), |mut counts, link| {
+            *counts.entry(link.dest.as_str()).or_insert(0) += 1;
+            counts
+        });
+        let links = collect_html(html)
+            .into_iter()
+            .filter(|link| match checked.get_mut(link.dest.as_str()) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+        self.check(path, page, &links)
+    }
+
+    /// Check the links written in `source`, which is generated to `page`
+    /// in the output directory
+    pub fn check(&self, source: &Path, page: &str, links: &[Link]) -> Vec<SpeedyError> {
+        let mut ids = HashMap::new();
+        links
+            .iter()
+            .filter_map(|link| {
+                self.check_link(page, link, &mut ids)
+                    .err()
+                    .map(|message| SpeedyError::BrokenLink {
+                        path: source.to_owned(),
+                        location: Some(link.location),
+                        message: format!("{}: {}", link.dest, message),
+                    })
+            })
+            .collect()
+    }
+
+    fn check_link(
+        &self,
+        page: &str,
+        link: &Link,
+        ids: &mut HashMap<String, Option<HashSet<String>>>,
+    ) -> std::result::Result<(), String> {
+        let dest = link
+            .dest
+            .strip_prefix(self.url_base)
+            .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+            .map(|rest| if rest.is_empty() { "/" } else { rest })
+            .unwrap_or(&link.dest);
+        if filters::has_scheme(dest) || dest.starts_with("//") {
+            return Ok(());
+        }
+        let (path, fragment) = match dest.find('#') {
+            Some(i) => (&dest[..i], Some(percent_decode(&dest[i + 1..]))),
+            None => (dest, None),
+        };
+        let path = path.split('?').next().unwrap_or_default();
+        let target = if path.is_empty() {
+            page.to_owned()
+        } else {
+            self.resolve(page, &percent_decode(path))
+                .ok_or_else(|| "no such page".to_owned())?
+        };
+        match fragment {
+            Some(fragment) if !fragment.is_empty() => {
+                let target_ids = ids
+                    .entry(target.clone())
+                    .or_insert_with(|| self.ids(&target));
+                match target_ids {
+                    Some(target_ids) if !target_ids.contains(&fragment) => {
+                        Err(format!("no element with id {:?} in {}", fragment, target))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The generated file that `path` refers to, when linked to from
+    /// `page`. Paths to directories refer to their `index.html`.
+    fn resolve(&self, page: &str, path: &str) -> Option<String> {
+        let mut parts = if path.starts_with('/') {
+            Vec::new()
+        } else {
+            page.split('/').collect::<Vec<_>>()
+        };
+        // the page itself, or the empty part after a trailing slash
+        parts.pop();
+        for part in path.split('/') {
+            match part {
+                "" | "." => (),
+                ".." => {
+                    parts.pop()?;
+                }
+                part => parts.push(part),
+            }
+        }
+        let file = parts.join("/");
+        let index = if file.is_empty() {
+            "index.html".to_owned()
+        } else {
+            format!("{}/index.html", file)
+        };
+        [file, index]
+            .iter()
+            .find(|candidate| self.outputs.contains(*candidate))
+            .cloned()
+    }
+
+    /// Every id in a generated page, or None if we can't tell, e.g.
+    /// because it isn't HTML
+    fn ids(&self, path: &str) -> Option<HashSet<String>> {
+        self.pages.get(path).map(|html| html_ids(html))
+    }
+}
+
+/// Every `id` and `name` attribute value in some HTML
+fn html_ids(html: &str) -> HashSet<String> {
+    let mut ids = HashSet::new();
+    for attr in &[" id=\"", " name=\""] {
+        for (start, _) in html.match_indices(attr) {
+            let value = &html[start + attr.len()..];
+            if let Some(end) = value.find('"') {
+                ids.insert(value[..end].to_owned());
+            }
+        }
+    }
+    ids
+}
+
+/// Decode `%XX` escapes, leaving anything that isn't one as-is
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i + 1..i + 3) {
+            Some(hex) if bytes[i] == b'%' => std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL_BASE: &str = "https://example.com/blog/";
+
+    fn checker<'a>(pages: &[(&'a str, &'a str)]) -> LinkChecker<'a> {
+        let outputs = pages
+            .iter()
+            .map(|(path, _)| path.to_string())
+            .chain(vec!["css/main.css".to_owned()])
+            .collect();
+        LinkChecker::new(URL_BASE, outputs, pages.iter().cloned().collect())
+    }
+
+    fn link(dest: &str) -> Link {
+        Link {
+            dest: dest.to_owned(),
+            location: Location::new(1, 1),
+        }
+    }
+
+    /// The links from `page` that are broken
+    fn broken<'a>(checker: &LinkChecker, page: &str, dests: &[&'a str]) -> Vec<&'a str> {
+        dests
+            .iter()
+            .filter(|dest| {
+                !checker
+                    .check(Path::new(page), page, &[link(dest)])
+                    .is_empty()
+            })
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn resolve_relative_and_absolute_paths() {
+        let checker = checker(&[
+            ("index.html", ""),
+            ("posts/a.html", ""),
+            ("posts/b.html", ""),
+            ("tags/index.html", ""),
+        ]);
+        let resolve = |path| checker.resolve("posts/a.html", path);
+        assert_eq!(resolve("b.html").as_deref(), Some("posts/b.html"));
+        assert_eq!(resolve("./b.html").as_deref(), Some("posts/b.html"));
+        assert_eq!(resolve("../index.html").as_deref(), Some("index.html"));
+        assert_eq!(resolve("/css/main.css").as_deref(), Some("css/main.css"));
+        assert_eq!(resolve("../../index.html"), None);
+        assert_eq!(resolve("c.html"), None);
+        // Directories fall back to their index.html
+        assert_eq!(resolve("/").as_deref(), Some("index.html"));
+        assert_eq!(resolve("..").as_deref(), Some("index.html"));
+        assert_eq!(resolve("/tags/").as_deref(), Some("tags/index.html"));
+        assert_eq!(resolve("../tags").as_deref(), Some("tags/index.html"));
+    }
+
+    #[test]
+    fn check_fragments() {
+        let checker = checker(&[
+            ("posts/a.html", r#"<h2 id="intro">Intro</h2>"#),
+            ("posts/b.html", r#"<a name="part%202"></a><h2 id="part 2">"#),
+        ]);
+        assert_eq!(
+            broken(
+                &checker,
+                "posts/a.html",
+                &[
+                    "#intro",
+                    "#",
+                    "b.html#part%202",
+                    "/posts/b.html#part 2",
+                    "/css/main.css#anything",
+                    "#missing",
+                    "b.html#intro",
+                ]
+            ),
+            vec!["#missing", "b.html#intro"]
+        );
+    }
+
+    #[test]
+    fn check_links_with_the_url_base() {
+        let checker = checker(&[("index.html", ""), ("posts/a.html", r#"<p id="x">"#)]);
+        assert_eq!(
+            broken(
+                &checker,
+                "index.html",
+                &[
+                    "https://example.com/blog",
+                    "https://example.com/blog/",
+                    "https://example.com/blog/posts/a.html#x",
+                    "https://example.com/blog/posts/a.html?page=2",
+                    "https://example.com/blogroll.html",
+                    "https://other.org/missing.html",
+                    "//cdn.example.org/x.js",
+                    "mailto:me@example.com",
+                    "https://example.com/blog/posts/missing.html",
+                    "https://example.com/blog/posts/a.html#y",
+                ]
+            ),
+            vec![
+                "https://example.com/blog/posts/missing.html",
+                "https://example.com/blog/posts/a.html#y",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_page_skips_links_already_checked() {
+        let html = r#"<a href="missing.html">1</a> <a href="missing.html">2</a>"#;
+        let checker = checker(&[("posts/a.html", html)]);
+        let path = Path::new("posts/a.html");
+        assert_eq!(checker.check_page(path, "posts/a.html", &[]).len(), 2);
+        // The post wrote one of the links, so only the other is reported
+        let errors = checker.check_page(path, "posts/a.html", &[link("missing.html")]);
+        assert_eq!(errors.len(), 1);
+        match &errors[0] {
+            SpeedyError::BrokenLink { location, .. } => {
+                assert_eq!(*location, Some(Location::new(1, 39)))
+            }
+            e => panic!("unexpected error: {}", e),
+        }
+        assert!(checker
+            .check_page(
+                path,
+                "posts/a.html",
+                &[link("missing.html"), link("missing.html")]
+            )
+            .is_empty());
+    }
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("a%20b"), "a b");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    }
+
+    #[test]
+    fn collect_html_unescapes_and_locates() {
+        let links = collect_html("<p>\n  <a href=\"/a?x=1&amp;y=2\"><img src=\"b.png\"></a>");
+        let found = links
+            .iter()
+            .map(|l| (l.dest.as_str(), l.location))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("/a?x=1&y=2", Location::new(2, 12)),
+                ("b.png", Location::new(2, 38)),
+            ]
+        );
+    }
+}
@@ -1,295 +1,254 @@
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::iter::FromIterator;
-use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::vec::IntoIter;
 
-use chrono::{DateTime, Local, NaiveDate, Utc};
-use clap::{App, ArgMatches, SubCommand};
-use liquid;
-use pulldown_cmark::{html, Event, Options as MDOptions, Parser as MDParser, Tag, CowStr};
-use warp;
-
-const IDX_NUM_RECENT_POSTS: u8 = 10;
-const URL_BASE: &'static str = "https://blog.mplanchard.com";
-
-#[derive(Debug)]
-struct Metadata {
-    title: String,
-    slug: String,
-    created: NaiveDate,
-    updated: NaiveDate,
-    tags: Vec<String>,
-    summary: String,
-}
-impl Metadata {
-    const NUM_HEADER_LNS: u8 = 6;
-    const TAG_DELIMITER: &'static str = ",";
-    const DATE_FMT: &'static str = "%Y-%m-%d";
-
-    fn new<S: AsRef<str>>(header_text: S) -> Self {
-        let headers = Self::header_map(&header_text);
-        let get_value = |v: &str| Self::header_value(&headers, v);
-
-        Metadata {
-            title: get_value(&"title").into(),
-            slug: get_value(&"slug").into(),
-            created: Self::date(get_value(&"created")),
-            updated: Self::date(get_value(&"updated")),
-            tags: Self::tags(get_value(&"tags")),
-            summary: get_value(&"summary").into(),
-        }
-    }
-
-    fn header_map<'a, S: AsRef<str>>(header_text: &'a S) -> HashMap<&'a str, &'a str> {
-        let lines = header_text
-            .as_ref()
-            .lines()
-            .take(Self::NUM_HEADER_LNS.into());
-        let ln_tuples = lines.map(|ln| {
-            let mut parts = ln.splitn(2, ":").map(|i| i.trim());
-            (
-                parts.next().expect(&format!("bad header: {:?}", ln)),
-                parts.next().expect(&format!("bad header: {:?}", ln)),
-            )
-        });
-        ln_tuples.collect()
-    }
-
-    fn header_value<'a>(headers: &'a HashMap<&str, &str>, key: &str) -> &'a str {
-        headers.get(key).expect(&format!("No {:?} header", key))
-    }
-
-    fn tags<S: AsRef<str>>(tags: S) -> Vec<String> {
-        tags.as_ref()
-            .split(Self::TAG_DELIMITER)
-            .map(|s| s.trim())
-            .map(|s| s.to_owned())
-            .collect()
-    }
-
-    fn date<S: AsRef<str>>(date: S) -> NaiveDate {
-        NaiveDate::parse_from_str(date.as_ref(), Self::DATE_FMT)
-            .expect(&format!("invalid date: {:?}", date.as_ref()))
-    }
-}
+use chrono::{Local, NaiveDate};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use pulldown_cmark::{html, CodeBlockKind, Event, Options as MDOptions, Parser as MDParser, Tag};
+use rayon::prelude::*;
+
+mod cache;
+mod config;
+mod error;
+mod feeds;
+mod filters;
+mod highlight;
+mod links;
+mod metadata;
+mod search;
+mod serve;
+mod templates;
+mod toc;
+
+use cache::BuildCache;
+use config::{Config, TagConfig};
+use error::{Result, SpeedyError};
+use highlight::Highlighter;
+use links::{Link, LinkChecker};
+use metadata::Metadata;
+use templates::Templates;
+use toc::{Heading, HeadingIds, TocEntry};
+
+/// The layout that every post is wrapped in
+const POST_LAYOUT: &str = "post";
+/// The layout that the pages redirecting from a post's aliases use
+const REDIRECT_LAYOUT: &str = "redirect";
+/// The layout that every tag's page is wrapped in
+const TAG_LAYOUT: &str = "tag";
+/// The layout that every tag's Atom feed is rendered with
+const TAG_FEED_LAYOUT: &str = "tag-atom";
+/// Where the stylesheet for highlighted code is written
+const HIGHLIGHT_CSS_PATH: &str = "css/highlight.css";
+/// Where the list of redirects from posts' aliases is written, in the
+/// format used by Netlify and others
+const REDIRECTS_PATH: &str = "_redirects";
+/// Where the index that the search page queries is written
+const SEARCH_INDEX_PATH: &str = "search.json";
 
 #[derive(Debug)]
 struct Post {
     content: String,
+    /// Every link in the post, for checking that they go somewhere
+    links: Vec<Link>,
     metadata: Metadata,
-    rendered_summary: String,
+    path: PathBuf,
+    /// Hash of the post's markdown file
+    source_hash: String,
+    /// The post's content without any markup, for searching
+    text: String,
+    /// Empty unless the post has a table of contents
+    toc: Vec<TocEntry>,
     url: String,
 }
 impl Post {
-    fn new(post_summary_template: &liquid::Template, metadata: Metadata, content: String) -> Self {
-        let url = format!("{}/posts/{}.html", URL_BASE, &metadata.slug);
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        path: PathBuf,
+        metadata: Metadata,
+        content: String,
+        links: Vec<Link>,
+        source_hash: String,
+        text: String,
+        toc: Vec<TocEntry>,
+        url_base: &str,
+    ) -> Self {
+        let url = format!("{}/posts/{}.html", url_base, &metadata.slug);
         Self {
             content,
-            rendered_summary: Self::render_summary(&post_summary_template, &metadata),
+            links,
             metadata,
+            path,
+            source_hash,
+            text,
+            toc,
             url,
         }
     }
 
-    fn render_summary(template: &liquid::Template, metadata: &Metadata) -> String {
-        let globals = liquid::value::Object::from_iter(vec![
-            ("slug".into(), to_liquid_val(&metadata.slug)),
+    /// The post as seen by templates, e.g. `{{ post.title }}`
+    fn liquid_val(&self) -> liquid::value::Value {
+        let metadata = &self.metadata;
+        let tags = metadata
+            .tags
+            .iter()
+            .sorted_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()))
+            .map(to_liquid_val)
+            .collect();
+        liquid::value::Value::Object(liquid::value::Object::from_iter(vec![
             ("title".into(), to_liquid_val(&metadata.title)),
+            ("slug".into(), to_liquid_val(&metadata.slug)),
+            ("url".into(), to_liquid_val(&self.url)),
             ("summary".into(), to_liquid_val(&metadata.summary)),
-        ]);
-        template
-            .render(&globals)
-            .expect(&format!("couldn't render post summary: {:?}", metadata))
-    }
-}
-
-struct TemplateBlockStrings {
-    about: &'static str,
-    header: &'static str,
-    notfound: &'static str,
-}
-
-struct TemplatePageStrings {
-    about: &'static str,
-    atom: &'static str,
-    generic: &'static str,
-    index: &'static str,
-    post: &'static str,
-}
-
-struct TemplateSnippetStrings {
-    atom_entry: &'static str,
-    footer_common: &'static str,
-    footer_nav_content: &'static str,
-    footer_nav: &'static str,
-    head: &'static str,
-    index_content: &'static str,
-    posts_content: &'static str,
-    posts_post: &'static str,
-    tag_link: &'static str,
-    tag_posts: &'static str,
-}
-
-/// Raw template HTML
-struct TemplateStrings {
-    blocks: TemplateBlockStrings,
-    pages: TemplatePageStrings,
-    snippets: TemplateSnippetStrings,
-}
-
-const TEMPLATE_STRINGS: TemplateStrings = TemplateStrings {
-    blocks: TemplateBlockStrings {
-        about: include_str!("../templates/blocks/about.html"),
-        header: include_str!("../templates/blocks/header.html"),
-        notfound: include_str!("../templates/blocks/notfound.html"),
-    },
-    pages: TemplatePageStrings {
-        about: include_str!("../templates/pages/about.html"),
-        atom: include_str!("../templates/pages/atom.xml"),
-        generic: include_str!("../templates/pages/generic.html"),
-        index: include_str!("../templates/pages/index.html"),
-        post: include_str!("../templates/pages/post.html"),
-    },
-    snippets: TemplateSnippetStrings {
-        atom_entry: include_str!("../templates/snippets/atom-entry.xml"),
-        footer_common: include_str!("../templates/snippets/footer-common.html"),
-        footer_nav_content: include_str!("../templates/snippets/footer-nav-content.html"),
-        footer_nav: include_str!("../templates/snippets/footer-nav.html"),
-        head: include_str!("../templates/snippets/head.html"),
-        index_content: include_str!("../templates/snippets/index-content.html"),
-        posts_content: include_str!("../templates/snippets/posts-content.html"),
-        posts_post: include_str!("../templates/snippets/posts-post.html"),
-        tag_link: include_str!("../templates/snippets/tag-link.html"),
-        tag_posts: include_str!("../templates/snippets/tag-posts.html"),
-    },
-};
-
-struct PageTemplates {
-    about: liquid::Template,
-    atom: liquid::Template,
-    generic: liquid::Template,
-    index: liquid::Template,
-    post: liquid::Template,
-}
-impl PageTemplates {
-    fn new(parser: &liquid::Parser) -> Self {
-        let parse = |template_str| parse_template_str(parser, template_str);
-        Self {
-            about: parse(TEMPLATE_STRINGS.pages.about),
-            atom: parse(TEMPLATE_STRINGS.pages.atom),
-            generic: parse(TEMPLATE_STRINGS.pages.generic),
-            index: parse(TEMPLATE_STRINGS.pages.index),
-            post: parse(TEMPLATE_STRINGS.pages.post),
-        }
-    }
-}
-
-struct SnippetTemplates {
-    atom_entry: liquid::Template,
-    footer_common: liquid::Template,
-    footer_nav_content: liquid::Template,
-    footer_nav: liquid::Template,
-    head: liquid::Template,
-    index_content: liquid::Template,
-    posts_content: liquid::Template,
-    posts_post: liquid::Template,
-    tag_link: liquid::Template,
-    tag_posts: liquid::Template,
-}
-impl SnippetTemplates {
-    fn new(parser: &liquid::Parser) -> Self {
-        let parse = |template_str| parse_template_str(parser, template_str);
-        Self {
-            atom_entry: parse(TEMPLATE_STRINGS.snippets.atom_entry),
-            footer_common: parse(TEMPLATE_STRINGS.snippets.footer_common),
-            footer_nav_content: parse(TEMPLATE_STRINGS.snippets.footer_nav_content),
-            footer_nav: parse(TEMPLATE_STRINGS.snippets.footer_nav),
-            head: parse(TEMPLATE_STRINGS.snippets.head),
-            index_content: parse(TEMPLATE_STRINGS.snippets.index_content),
-            posts_content: parse(TEMPLATE_STRINGS.snippets.posts_content),
-            posts_post: parse(TEMPLATE_STRINGS.snippets.posts_post),
-            tag_link: parse(TEMPLATE_STRINGS.snippets.tag_link),
-            tag_posts: parse(TEMPLATE_STRINGS.snippets.tag_posts),
-        }
-    }
-}
-
-struct Templates {
-    pages: PageTemplates,
-    snippets: SnippetTemplates,
-}
-impl Templates {
-    fn new(parser: &liquid::Parser) -> Self {
-        Self {
-            pages: PageTemplates::new(parser),
-            snippets: SnippetTemplates::new(parser),
-        }
+            ("content".into(), to_liquid_val(&self.content)),
+            ("created".into(), date_liquid_val(&metadata.created)),
+            ("updated".into(), date_liquid_val(&metadata.updated)),
+            ("tags".into(), liquid::value::Value::Array(tags)),
+            ("draft".into(), liquid::value::Value::scalar(metadata.draft)),
+            (
+                "unlisted".into(),
+                liquid::value::Value::scalar(metadata.unlisted),
+            ),
+            (
+                "noindex".into(),
+                liquid::value::Value::scalar(metadata.noindex),
+            ),
+            ("toc".into(), toc::liquid_val(&self.toc)),
+            (
+                "series".into(),
+                metadata
+                    .series
+                    .as_ref()
+                    .map_or(liquid::value::Value::Nil, to_liquid_val),
+            ),
+            (
+                "extra".into(),
+                liquid::value::Value::Object(metadata.extra.clone()),
+            ),
+        ]))
     }
 }
 
-struct PreRenderedTemplates {
-    footer_common: String,
+/// The posts with a tag, including unlisted posts and, when they're being
+/// built, drafts. Tags that differ only in case or punctuation (e.g.
+/// `RustConf` and `rustconf`) are treated as the same.
+struct TagPosts<'a> {
+    /// The tag as written in the newest post that has it
+    name: &'a str,
+    posts: Vec<&'a Post>,
 }
-impl PreRenderedTemplates {
-    fn new(templates: &Templates) -> Self {
-        Self {
-            footer_common: Self::render_footer_common(&templates.snippets.footer_common),
-        }
-    }
-
-    fn render_footer_common(template: &liquid::Template) -> String {
-        let today = format!("{}", Local::today().format("%Y"));
-        let globals = liquid::value::Object::from_iter(vec![("year".into(), to_liquid_val(today))]);
-
-        template
-            .render(&globals)
-            .expect("failed to render footer license template")
+impl<'a> TagPosts<'a> {
+    /// Whether only drafts and unlisted posts have the tag, in which case
+    /// it gets a page so that they can link to it, but no feed and no
+    /// place in the list of tags
+    fn is_unlisted(&self) -> bool {
+        !self.posts.iter().any(|p| is_listed(p))
     }
 }
 
 struct ContextData {
+    config: Config,
+    highlighter: Highlighter,
     posts: Vec<Post>,
-    pre_rendered: PreRenderedTemplates,
     templates: Templates,
 }
 impl ContextData {
-    fn new() -> Self {
-        let parser = liquid::ParserBuilder::with_liquid()
-            .build()
-            .expect("failed to build parser");
-        let templates = Templates::new(&parser);
-        let posts = Self::collect_posts(&templates.snippets.posts_post);
-        let pre_rendered = PreRenderedTemplates::new(&templates);
-        ContextData {
-            posts,
-            pre_rendered,
-            templates,
+    /// Parse all templates and posts, returning every error encountered
+    fn new(config: Config) -> std::result::Result<Self, Vec<SpeedyError>> {
+        let mut errors = Vec::new();
+        let templates = Templates::load(&config, &mut errors);
+        let highlighter = match Highlighter::new(&config) {
+            Ok(highlighter) => highlighter,
+            Err(e) => {
+                errors.push(e);
+                return Err(errors);
+            }
+        };
+        let posts = Self::collect_posts(&config, &highlighter, &mut errors);
+        if errors.is_empty() {
+            Ok(ContextData {
+                config,
+                highlighter,
+                posts,
+                templates,
+            })
+        } else {
+            Err(errors)
         }
     }
 
-    fn collect_posts(post_summary_template: &liquid::Template) -> Vec<Post> {
+    fn collect_posts(
+        config: &Config,
+        highlighter: &Highlighter,
+        errors: &mut Vec<SpeedyError>,
+    ) -> Vec<Post> {
         let md_opts = Self::get_md_opts();
-        let mut posts = files_from_dir("posts")
-            .map(|md| {
-                let md_txt =
-                    fs::read_to_string(md.path()).expect(&format!("couldn't read md: {:?}", md));
-                let metadata = Metadata::new(&md_txt);
-                let md_content = &md_txt
-                    .lines()
-                    .skip(Metadata::NUM_HEADER_LNS.into())
-                    .collect::<Vec<&str>>()
-                    .join("\n");
-                let content = md_to_html(&md_content, md_opts);
-                Post::new(&post_summary_template, metadata, content)
+        let mut dirs = vec![(&config.build.posts_dir, false)];
+        if config.build.drafts {
+            dirs.push((&config.build.drafts_dir, true));
+        }
+        let mut md_files = Vec::new();
+        for (dir, is_draft_dir) in dirs {
+            match files_from_dir(dir) {
+                Ok(files) => md_files.extend(files.into_iter().map(|f| (f.path(), is_draft_dir))),
+                Err(e) => errors.push(e),
+            }
+        }
+        // Results are collected in the same order as `md_files`, so the
+        // output doesn't depend on which thread finishes first
+        let results = md_files
+            .par_iter()
+            .map(|(path, is_draft_dir)| {
+                Self::read_post(path, config, highlighter, md_opts, *is_draft_dir)
             })
-            .collect::<Vec<Post>>();
+            .collect::<Vec<Result<Post>>>();
+        let mut posts = Vec::new();
+        for result in results {
+            match result {
+                Ok(post) if post.metadata.draft && !config.build.drafts => (),
+                Ok(post) => posts.push(post),
+                Err(e) => errors.push(e),
+            }
+        }
         // sort posts by date descending
         posts.sort_by(|a, b| a.metadata.created.cmp(&b.metadata.created).reverse());
         posts
     }
 
+    fn read_post(
+        path: &Path,
+        config: &Config,
+        highlighter: &Highlighter,
+        md_opts: MDOptions,
+        is_draft: bool,
+    ) -> Result<Post> {
+        let md_txt = fs::read_to_string(path).map_err(|e| SpeedyError::io(path, e))?;
+        let (mut metadata, md_content) = Metadata::from_post(path, &md_txt)?;
+        metadata.draft |= is_draft;
+        let (content, headings) = md_to_html(md_content, md_opts, highlighter);
+        let body_offset = md_content.as_ptr() as usize - md_txt.as_ptr() as usize;
+        let links = links::collect(&md_txt, md_content, body_offset, md_opts);
+        let toc = if metadata.toc.unwrap_or(config.toc.enabled) {
+            toc::build(&headings, config.toc.min_depth, config.toc.max_depth)
+        } else {
+            Vec::new()
+        };
+        Ok(Post::new(
+            path.to_owned(),
+            metadata,
+            content,
+            links,
+            cache::hash(&md_txt),
+            search::plain_text(md_content, md_opts),
+            toc,
+            &config.site.url_base,
+        ))
+    }
+
     fn get_md_opts() -> MDOptions {
         let mut options = MDOptions::empty();
         options.insert(MDOptions::ENABLE_FOOTNOTES);
@@ -302,359 +261,704 @@ impl ContextData {
 
 /// Maintain structs and data to be shared among rendering functions
 struct Context<'a> {
-    blocks: TemplateBlockStrings,
+    cache: &'a BuildCache,
+    config: &'a Config,
+    highlighter: &'a Highlighter,
     posts: &'a Vec<Post>,
-    pre_rendered: &'a PreRenderedTemplates,
-    tag_map: HashMap<&'a str, Vec<&'a Post>>,
+    /// The indices in `posts` of the parts of each series, in order,
+    /// keyed by the series' slug
+    series_map: HashMap<String, Vec<usize>>,
+    /// Each post as seen by templates, in the same order as `posts`
+    post_vals: Vec<liquid::value::Value>,
+    /// Hash of everything that listings of posts (the index, feed, etc.)
+    /// are rendered from
+    posts_inputs: String,
+    /// Hash of everything that every page is rendered from
+    site_inputs: String,
+    /// Keyed by the tag's slug
+    tag_map: HashMap<String, TagPosts<'a>>,
     templates: &'a Templates,
+    /// When the site is being built, which is how up to date it is if
+    /// there are no published posts
+    today: NaiveDate,
+    /// The current year, for copyright notices
+    year: String,
 }
 impl<'a> Context<'a> {
-    fn new(data: &'a ContextData) -> Self {
+    fn new(data: &'a ContextData, cache: &'a BuildCache) -> Self {
         let tag_map = Self::tag_map(&data.posts);
+        let series_map = Self::series_map(&data.posts);
+        let post_vals = data.posts.iter().map(Post::liquid_val).collect();
+        let today = Local::now().date_naive();
+        let year = today.format("%Y").to_string();
+        let site_inputs = Self::site_inputs(data, &year);
+        // The date matters only when there's no published post to take it
+        // from, but then the listings change along with it
+        let updated = last_updated(data.posts.iter().filter(|p| is_listed(p)), today);
+        let posts_inputs = cache::hash_all(
+            vec![site_inputs.as_str(), &updated.to_string()]
+                .into_iter()
+                .chain(
+                    data.posts
+                        .iter()
+                        .flat_map(|p| vec![p.source_hash.as_str(), draft_str(p)]),
+                ),
+        );
         Self {
-            blocks: TEMPLATE_STRINGS.blocks,
-            pre_rendered: &data.pre_rendered,
-            tag_map: tag_map,
+            cache,
+            config: &data.config,
+            highlighter: &data.highlighter,
+            posts_inputs,
+            site_inputs,
+            tag_map,
             posts: &data.posts,
+            series_map,
+            post_vals,
             templates: &data.templates,
+            today,
+            year,
         }
     }
 
-    fn generate_all(&self) {
-        self.generate_about_page();
-        self.generate_atom_page();
-        self.generate_index_page();
-        self.generate_notfound_page();
-        self.generate_post_pages();
-        self.generate_posts_page();
-        self.generate_tags_page();
-    }
-
-    fn generate_about_page(&self) {
-        fs::write("static/about.html", &self.render_about_page())
-            .expect("couldn't write about.html");
-    }
-
-    fn generate_atom_page(&self) {
-        fs::write("static/atom.xml", &self.render_atom_page()).expect("couldn't write atom.xml");
+    /// Hash the config, the templates, and anything else that may affect
+    /// the rendering of every page, including speedy itself
+    fn site_inputs(data: &ContextData, year: &str) -> String {
+        cache::hash_all(
+            vec![
+                cache::exe_hash(),
+                &data.config.hash,
+                data.highlighter.theme_hash.as_deref().unwrap_or_default(),
+                if data.config.build.drafts {
+                    "drafts"
+                } else {
+                    ""
+                },
+                year,
+            ]
+            .into_iter()
+            .chain(data.templates.hashes.iter().map(|(_, hash)| hash.as_str())),
+        )
     }
 
-    fn generate_index_page(&self) {
-        fs::write("static/index.html", &self.render_index_page())
-            .expect("failed to write index file");
+    /// Record the hash of every source file in the cache
+    fn record_sources(&self) {
+        self.cache
+            .add_source(self.config.path.to_string_lossy(), self.config.hash.clone());
+        for (name, hash) in &self.templates.hashes {
+            self.cache.add_source(name.as_str(), hash.clone());
+        }
+        if let Some(hash) = &self.highlighter.theme_hash {
+            self.cache
+                .add_source(self.config.build.highlight_theme.as_str(), hash.clone());
+        }
+        for post in self.posts {
+            self.cache
+                .add_source(post.path.to_string_lossy(), post.source_hash.clone());
+        }
     }
 
-    fn generate_notfound_page(&self) {
-        fs::write("static/notfound.html", &self.render_notfound_page())
-            .expect("failed to write 404 file");
+    /// Generate every page, returning any errors encountered
+    fn generate_all(&self) -> Vec<SpeedyError> {
+        self.record_sources();
+        let mut errors = self.copy_assets();
+        errors.extend(self.generate_highlight_css());
+        errors.extend(self.generate_pages());
+        errors.extend(self.generate_post_pages());
+        errors.extend(self.generate_tag_pages());
+        errors.extend(self.generate_search_index());
+        errors.extend(self.generate_redirects());
+        errors
     }
 
-    fn generate_post_page<S: AsRef<str>, T: AsRef<str>>(&self, slug: S, content: T) {
-        fs::write(
-            format!("static/posts/{}.html", slug.as_ref()),
-            content.as_ref(),
-        )
-        .expect(&format!("couldn't write post: {}", slug.as_ref()));
+    /// Check that every link in every post goes to a page that was
+    /// generated, and that any `#fragment` it has is an id on that page
+    ///
+    /// Links written in posts are reported where they were written, and
+    /// any others (e.g. from templates) in the generated page.
+    fn check_links(&self) -> Vec<SpeedyError> {
+        let outputs = self.relative_outputs();
+        let mut errors = Vec::new();
+        let mut html = Vec::new();
+        for page in outputs.iter().filter(|path| path.ends_with(".html")) {
+            match self.cache.read(&self.output_path(page)) {
+                Ok(contents) => html.push((
+                    page.as_str(),
+                    String::from_utf8_lossy(&contents).into_owned(),
+                )),
+                Err(e) => errors.push(e),
+            }
+        }
+        let pages = html
+            .iter()
+            .map(|(page, html)| (*page, html.as_str()))
+            .collect();
+        let checker = LinkChecker::new(&self.config.site.url_base, outputs.clone(), pages);
+        let post_pages = self
+            .posts
+            .iter()
+            .map(|post| (post_page_path(post), post))
+            .collect::<HashMap<_, _>>();
+        errors.extend(
+            self.posts
+                .par_iter()
+                .flat_map(|post| checker.check(&post.path, &post_page_path(post), &post.links))
+                .collect::<Vec<_>>(),
+        );
+        errors.extend(
+            html.par_iter()
+                .flat_map(|(page, _)| {
+                    let checked = post_pages.get(*page).map_or(&[][..], |post| &post.links);
+                    checker.check_page(&self.output_path(page), page, checked)
+                })
+                .collect::<Vec<_>>(),
+        );
+        errors
     }
 
-    fn generate_post_pages(&self) {
-        for (i, post) in self.posts.iter().enumerate() {
-            self.generate_post_page(&post.metadata.slug, &self.render_post_page(i, &post))
-        }
+    /// Every file generated in this build, relative to the output
+    /// directory, e.g. `posts/why-blog.html`
+    fn relative_outputs(&self) -> BTreeSet<String> {
+        let output_dir = Path::new(&self.config.build.output_dir);
+        self.cache
+            .outputs()
+            .iter()
+            .filter_map(|path| {
+                Path::new(path)
+                    .strip_prefix(output_dir)
+                    .ok()
+                    .map(|p| p.to_string_lossy().replace('\\', "/"))
+            })
+            .collect()
     }
 
-    fn generate_posts_page(&self) {
-        fs::write("static/posts.html", &self.render_posts_page())
-            .expect("failed to write index file");
+    /// The path of a generated file, relative to the output directory
+    fn output_path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        Path::new(&self.config.build.output_dir).join(path)
     }
 
-    fn generate_tags_page(&self) {
-        fs::write("static/tags.html", &self.render_tags_page()).expect("failed to write tags file");
+    /// Write a rendered file via the cache, checking it first if it's a
+    /// feed so that a broken one is never published
+    fn write_rendered<F>(&self, path: PathBuf, inputs: &str, render: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<String>,
+    {
+        self.cache.write(&path, inputs, || {
+            let contents = render()?;
+            feeds::check(&path, &contents)?;
+            Ok(contents)
+        })
     }
 
-    fn generic_globals_vec<S: AsRef<str>, T: AsRef<str>>(
-        &self,
-        title: S,
-        content: T,
-    ) -> Vec<(Cow<'static, str>, liquid::value::Value)> {
-        let head = self.render_head_block(String::from(title.as_ref()));
-        vec![
-            ("head".into(), to_liquid_val(head)),
-            ("header".into(), to_liquid_val(&self.blocks.header)),
-            ("content".into(), to_liquid_val(content)),
-            (
-                "footer-common".into(),
-                to_liquid_val(&self.pre_rendered.footer_common),
-            ),
-        ]
+    /// Copy hand-maintained files (CSS, fonts, etc.) into the output directory
+    fn copy_assets(&self) -> Vec<SpeedyError> {
+        let assets_dir = Path::new(&self.config.build.assets_dir);
+        let files = match cache::files_in_tree(assets_dir) {
+            Ok(files) => files,
+            Err(e) => return vec![e],
+        };
+        files
+            .par_iter()
+            .map(|file| {
+                let contents = fs::read(file).map_err(|e| SpeedyError::io(file, e))?;
+                let relative = file
+                    .strip_prefix(assets_dir)
+                    .expect("asset outside assets dir");
+                self.cache
+                    .write(&self.output_path(relative), &cache::hash(&contents), || {
+                        Ok(contents)
+                    })
+            })
+            .filter_map(|r| r.err())
+            .collect()
     }
 
-    fn generic_globals<S: AsRef<str>, T: AsRef<str>>(
-        &self,
-        title: S,
-        content: T,
-    ) -> liquid::value::Object {
-        liquid::value::Object::from_iter(self.generic_globals_vec(title, content))
+    /// Write the stylesheet for highlighted code blocks
+    fn generate_highlight_css(&self) -> Option<SpeedyError> {
+        let css = self.highlighter.css();
+        self.cache
+            .write(
+                &self.output_path(HIGHLIGHT_CSS_PATH),
+                &cache::hash(&css),
+                || Ok(css),
+            )
+            .err()
     }
 
-    fn render_about_page(&self) -> String {
-        let globals = self.generic_globals("About", &self.blocks.about);
+    /// Render every template in the pages directory to the same path in
+    /// the output directory
+    fn generate_pages(&self) -> Vec<SpeedyError> {
+        let globals = self.page_globals();
         self.templates
             .pages
-            .about
-            .render(&globals)
-            .expect("failed to render head template")
+            .par_iter()
+            .map(|(path, page)| {
+                self.write_rendered(self.output_path(path), &self.posts_inputs, || {
+                    self.templates.render_page(page, &globals)
+                })
+            })
+            .filter_map(|r| r.err())
+            .collect()
     }
 
-    fn updated_datetime_str(date: &NaiveDate) -> String {
-        DateTime::<Utc>::from_utc(date.and_hms(0, 0, 0), Utc).to_rfc3339()
+    fn generate_post_page(&self, index: usize, post: &Post) -> Result<()> {
+        // Post pages link to their neighbors, so they depend on those too
+        let neighbor_hash = |i: Option<usize>| {
+            i.and_then(|i| self.posts.get(i))
+                .map_or("", |p| p.source_hash.as_str())
+        };
+        // and on every part of their series
+        let series_hashes = self
+            .series_parts(post)
+            .iter()
+            .map(|&i| self.posts[i].source_hash.as_str());
+        let inputs = cache::hash_all(
+            vec![
+                self.site_inputs.as_str(),
+                &post.source_hash,
+                draft_str(post),
+                neighbor_hash(index.checked_sub(1)),
+                neighbor_hash(Some(index + 1)),
+            ]
+            .into_iter()
+            .chain(series_hashes),
+        );
+        self.cache
+            .write(&self.output_path(post_page_path(post)), &inputs, || {
+                self.render_post_page(index, post)
+            })
     }
 
-    fn render_atom_entry(&self, post: &Post) -> String {
-        let globals = liquid::value::Object::from_iter(vec![
-            ("title".into(), to_liquid_val(&post.metadata.title)),
-            ("link".into(), to_liquid_val(&post.url)),
-            (
-                "updated".into(),
-                to_liquid_val(Self::updated_datetime_str(&post.metadata.updated)),
-            ),
-            ("summary".into(), to_liquid_val(&post.metadata.summary)),
-        ]);
-        self.templates
-            .snippets
-            .atom_entry
-            .render(&globals)
-            .expect(&format!("failed to reader atom entry for {:?}", post))
+    fn generate_post_pages(&self) -> Vec<SpeedyError> {
+        self.posts
+            .par_iter()
+            .enumerate()
+            .map(|(i, post)| self.generate_post_page(i, post))
+            .filter_map(|r| r.err())
+            .collect()
     }
 
-    fn render_atom_page(&self) -> String {
-        let (updated, entries) = self.posts.iter().fold(
-            (NaiveDate::from_ymd(1, 1, 1), String::new()),
-            |(newest_date, entries), post| {
-                (
-                    if post.metadata.updated > newest_date {
-                        post.metadata.updated
-                    } else {
-                        newest_date
-                    },
-                    [entries, self.render_atom_entry(post)].join("\n"),
+    /// Generate a page redirecting to its post for every alias, along with
+    /// a manifest of them all. Aliases may not clash with each other or
+    /// with any other generated file, so this must run after everything
+    /// else has been written.
+    fn generate_redirects(&self) -> Vec<SpeedyError> {
+        // Pages that failed to render aren't in the outputs, but they're
+        // still taken
+        let mut taken = self
+            .relative_outputs()
+            .into_iter()
+            .chain(self.templates.pages.keys().cloned())
+            .chain(self.posts.iter().map(post_page_path))
+            .chain(self.tag_map.keys().flat_map(|slug| {
+                vec![
+                    format!("tags/{}.html", slug),
+                    format!("tags/{}/atom.xml", slug),
+                ]
+            }))
+            .chain(vec![
+                HIGHLIGHT_CSS_PATH.to_owned(),
+                cache::MANIFEST_NAME.to_owned(),
+                REDIRECTS_PATH.to_owned(),
+                SEARCH_INDEX_PATH.to_owned(),
+            ])
+            .collect::<HashSet<_>>();
+        let mut errors = Vec::new();
+        let mut redirects = Vec::new();
+        for (index, post) in self.posts.iter().enumerate() {
+            for alias in &post.metadata.aliases {
+                if taken.insert(alias.clone()) {
+                    redirects.push((alias, index));
+                } else {
+                    errors.push(SpeedyError::FrontMatter {
+                        path: post.path.clone(),
+                        location: None,
+                        message: format!(
+                            "alias {:?} is already a generated file or another alias",
+                            alias
+                        ),
+                    });
+                }
+            }
+        }
+        redirects.sort();
+        errors.extend(
+            redirects
+                .par_iter()
+                .map(|&(alias, index)| {
+                    let post = &self.posts[index];
+                    let inputs = cache::hash_all(vec![
+                        self.site_inputs.as_str(),
+                        &post.source_hash,
+                        draft_str(post),
+                    ]);
+                    self.cache.write(&self.output_path(alias), &inputs, || {
+                        self.render_redirect_page(index, post)
+                    })
+                })
+                .filter_map(|r| r.err())
+                .collect::<Vec<_>>(),
+        );
+        let manifest = redirects
+            .iter()
+            .map(|&(alias, index)| {
+                let from = match alias.strip_suffix("index.html") {
+                    Some(dir) if dir.is_empty() || dir.ends_with('/') => dir,
+                    _ => alias,
+                };
+                format!("/{} /{} 301\n", from, post_page_path(&self.posts[index]))
+            })
+            .collect::<String>();
+        errors.extend(
+            self.cache
+                .write(
+                    &self.output_path(REDIRECTS_PATH),
+                    &cache::hash(&manifest),
+                    || Ok(manifest),
                 )
-            },
+                .err(),
         );
-        let globals = liquid::value::Object::from_iter(vec![
-            (
-                "updated".into(),
-                to_liquid_val(Self::updated_datetime_str(&updated)),
-            ),
-            ("entries".into(), to_liquid_val(entries)),
-        ]);
-        self.templates
-            .pages
-            .atom
-            .render(&globals)
-            .expect("failed to render atom feed")
-    }
-
-    fn render_footer_inner_content_block<S: AsRef<str>, T: AsRef<str>>(
-        &self,
-        slug: Option<S>,
-        description: T,
-    ) -> String {
-        if let Some(s) = slug {
-            let globals = liquid::value::Object::from_iter(vec![
-                ("slug".into(), to_liquid_val(s)),
-                ("description".into(), to_liquid_val(description)),
-            ]);
-            self.templates
-                .snippets
-                .footer_nav_content
-                .render(&globals)
-                .expect("failed to render footer nav content")
-        } else {
-            "".into()
-        }
+        errors
     }
 
-    fn render_footer_nav_block<S: AsRef<str>, T: AsRef<str>>(
-        &self,
-        prev_slug: Option<S>,
-        next_slug: Option<T>,
-    ) -> String {
-        let left_content = self.render_footer_inner_content_block(prev_slug, "&lt previous");
-        let right_content = self.render_footer_inner_content_block(next_slug, "next &gt");
-
-        let footer_nav_globals = liquid::value::Object::from_iter(vec![
-            ("left_content".into(), to_liquid_val(left_content)),
-            ("right_content".into(), to_liquid_val(right_content)),
-        ]);
-
-        self.templates
-            .snippets
-            .footer_nav
-            .render(&footer_nav_globals)
-            .expect("failed to render footer nav template")
+    /// Write the index the search page queries, with every published post
+    /// that isn't `unlisted`
+    fn generate_search_index(&self) -> Option<SpeedyError> {
+        let entries = self
+            .posts
+            .iter()
+            .filter(|post| is_listed(post))
+            .map(|post| search::Entry {
+                title: &post.metadata.title,
+                url: format!("/{}", post_page_path(post)),
+                summary: filters::strip_html(&post.metadata.summary),
+                tags: &post.metadata.tags,
+                created: post.metadata.created.to_string(),
+                text: search::words(&post.text),
+            })
+            .collect::<Vec<_>>();
+        self.cache
+            .write(
+                &self.output_path(SEARCH_INDEX_PATH),
+                &self.posts_inputs,
+                || Ok(search::index(&entries)),
+            )
+            .err()
     }
 
-    fn render_generic_page(&self, title: &str, content: &str) -> String {
-        let globals = self.generic_globals(&title, &content);
-        self.templates
-            .pages
-            .generic
-            .render(&globals)
-            .expect("failed to render generic template")
+    /// Generate the page and the Atom feed for every tag
+    fn generate_tag_pages(&self) -> Vec<SpeedyError> {
+        self.tag_map
+            .par_iter()
+            .flat_map(|(slug, tag)| {
+                let inputs = cache::hash_all(
+                    vec![self.site_inputs.as_str(), slug]
+                        .into_iter()
+                        .chain(tag.posts.iter().map(|p| p.source_hash.as_str())),
+                );
+                let mut pages = vec![(format!("tags/{}.html", slug), TAG_LAYOUT)];
+                if !tag.is_unlisted() {
+                    pages.push((format!("tags/{}/atom.xml", slug), TAG_FEED_LAYOUT));
+                }
+                pages.into_par_iter().map(move |(path, layout)| {
+                    self.write_rendered(self.output_path(path), &inputs, || {
+                        self.render_tag_page(slug, tag, layout)
+                    })
+                })
+            })
+            .filter_map(|r| r.err())
+            .collect()
     }
 
-    fn render_head_block(&self, title: String) -> String {
-        let globals = liquid::value::Object::from_iter(vec![(
-            "title".into(),
-            liquid::value::Value::scalar(title),
-        )]);
-        self.templates
-            .snippets
-            .head
-            .render(&globals)
-            .expect("failed to render head template")
+    /// Variables available to every template
+    fn common_globals(&self) -> Vec<(Cow<'static, str>, liquid::value::Value)> {
+        vec![
+            ("site".into(), site_liquid_val(self.config)),
+            ("year".into(), to_liquid_val(&self.year)),
+        ]
     }
 
-    fn render_index_page(&self) -> String {
-        let index_content_globals = liquid::value::Object::from_iter(vec![(
+    /// Variables available to the templates in the pages directory. Along
+    /// with the site config, `site` has every post (`site.posts`), the
+    /// most recent listed posts (`site.recent_posts`), every tag
+    /// (`site.tags`), every HTML page (`site.pages`), and when the newest
+    /// listed post was updated (`site.updated`, which is the build date
+    /// if there are none). `tags` has each tag by its slug.
+    fn page_globals(&self) -> liquid::value::Object {
+        let listed = || {
+            self.posts
+                .iter()
+                .zip(&self.post_vals)
+                .filter(|(p, _)| is_listed(p))
+                .map(|(_, val)| val.clone())
+        };
+        let mut tags = self.tag_map.iter().collect::<Vec<_>>();
+        tags.sort_by_key(|(_, tag)| tag.name.to_lowercase());
+        let tag_vals = tags
+            .into_iter()
+            .map(|(slug, tag)| (slug, self.tag_liquid_val(slug, tag)))
+            .collect::<Vec<_>>();
+
+        let mut site = match site_liquid_val(self.config) {
+            liquid::value::Value::Object(site) => site,
+            _ => unreachable!("site config is always an object"),
+        };
+        site.insert(
             "posts".into(),
-            to_liquid_val(
-                self.posts
+            liquid::value::Value::Array(self.post_vals.clone()),
+        );
+        site.insert(
+            "recent_posts".into(),
+            liquid::value::Value::Array(listed().take(self.config.site.num_recent_posts).collect()),
+        );
+        site.insert(
+            "tags".into(),
+            liquid::value::Value::Array(
+                tag_vals
                     .iter()
-                    .take(IDX_NUM_RECENT_POSTS.into())
-                    .map(|p| p.rendered_summary.as_str())
-                    .collect::<Vec<&str>>()
-                    .join("\n"),
+                    .filter(|(slug, _)| !self.tag_map[slug.as_str()].is_unlisted())
+                    .map(|(_, val)| val.clone())
+                    .collect(),
             ),
-        )]);
-        let index_content = self
-            .templates
-            .snippets
-            .index_content
-            .render(&index_content_globals)
-            .expect("couldn't render index content");
-        let index_globals = self.generic_globals("Home", &index_content);
+        );
+        site.insert("pages".into(), self.pages_liquid_val());
+        let updated = last_updated(self.posts.iter().filter(|p| is_listed(p)), self.today);
+        site.insert("updated".into(), date_liquid_val(&updated));
 
-        self.templates
-            .pages
-            .index
-            .render(&index_globals)
-            .expect("couldn't render index")
+        let mut globals = self.common_globals();
+        globals.extend(vec![
+            ("site".into(), liquid::value::Value::Object(site)),
+            (
+                "tags".into(),
+                liquid::value::Value::Object(
+                    tag_vals
+                        .into_iter()
+                        .map(|(slug, val)| (slug.clone().into(), val))
+                        .collect(),
+                ),
+            ),
+        ]);
+        liquid::value::Object::from_iter(globals)
     }
 
-    fn render_notfound_page(&self) -> String {
-        self.render_generic_page("Not Found", &self.blocks.notfound)
+    /// Every HTML page's front matter, along with its `url`, e.g. for
+    /// listing them in the sitemap
+    fn pages_liquid_val(&self) -> liquid::value::Value {
+        liquid::value::Value::Array(
+            self.templates
+                .pages
+                .iter()
+                .filter(|(path, _)| path.ends_with(".html"))
+                .map(|(path, page)| {
+                    let url = match path.strip_suffix("index.html") {
+                        Some(dir) => format!("/{}", dir),
+                        None => format!("/{}", path),
+                    };
+                    let mut val = page.front_matter.clone();
+                    val.insert("url".into(), to_liquid_val(&url));
+                    liquid::value::Value::Object(val)
+                })
+                .collect(),
+        )
     }
 
-    fn render_posts_page(&self) -> String {
-        let posts_content_globals = liquid::value::Object::from_iter(vec![(
-            "posts".into(),
-            to_liquid_val(
-                &self
-                    .posts
-                    .iter()
-                    .map(|p| p.rendered_summary.as_str())
-                    .collect::<Vec<&str>>()
-                    .join("\n"),
+    /// Post pages have the post itself as `post`, along with the posts
+    /// before and after it as `previous` and `next`, if there are any.
+    /// Posts in a series also have the series as `series`.
+    fn render_post_page(&self, index: usize, post: &Post) -> Result<String> {
+        let mut globals = self.common_globals();
+        globals.extend(vec![
+            ("post".into(), self.post_vals[index].clone()),
+            (
+                "page".into(),
+                liquid::value::Value::Object(liquid::value::Object::from_iter(vec![(
+                    "title".into(),
+                    to_liquid_val(&post.metadata.title),
+                )])),
             ),
-        )]);
-        let posts_content = self
-            .templates
-            .snippets
-            .posts_content
-            .render(&posts_content_globals)
-            .expect("couldn't render posts content");
+        ]);
+        // prev is next in vec, and next is prev. Unlisted posts are
+        // skipped, so that they can only be found by their links.
+        let listed = |i: &usize| !self.posts[*i].metadata.unlisted;
+        if let Some(previous) = (index + 1..self.posts.len()).find(listed) {
+            globals.push(("previous".into(), self.post_vals[previous].clone()));
+        }
+        if let Some(next) = (0..index).rev().find(listed) {
+            globals.push(("next".into(), self.post_vals[next].clone()));
+        }
+        if let Some(series) = self.series_liquid_val(index, post) {
+            globals.push(("series".into(), series));
+        }
+        self.templates.render_layouts(
+            &post.path.display().to_string(),
+            post.content.clone(),
+            Some(POST_LAYOUT.into()),
+            liquid::value::Object::from_iter(globals),
+        )
+    }
 
-        self.render_generic_page("Posts", &posts_content)
+    /// Redirect pages have the post they redirect to as `post`
+    fn render_redirect_page(&self, index: usize, post: &Post) -> Result<String> {
+        let mut globals = self.common_globals();
+        globals.push(("post".into(), self.post_vals[index].clone()));
+        self.templates.render_layouts(
+            &post.path.display().to_string(),
+            String::new(),
+            Some(REDIRECT_LAYOUT.into()),
+            liquid::value::Object::from_iter(globals),
+        )
     }
 
-    fn render_post_page(&self, index: usize, post: &Post) -> String {
-        // let head = self.render_head_block(post.metadata.title.to_owned());
-        let footer_nav = self.render_footer_nav_block(
-            // prev is next in vec
-            self.posts
-                .get(index + 1)
-                .map(|p| p.metadata.slug.to_owned()),
-            // next is prev in vec
-            if index > 0 {
-                Some(self.posts[index - 1].metadata.slug.to_owned())
-            } else {
-                None
-            },
-        );
-        let mut globals_vec = self.generic_globals_vec(&post.metadata.title, &post.content);
-        globals_vec.extend(vec![
+    /// Tag pages and feeds have the tag as `tag`, and its description (if
+    /// any) as their content
+    fn render_tag_page(&self, slug: &str, tag: &TagPosts, layout: &str) -> Result<String> {
+        let mut globals = self.common_globals();
+        globals.extend(vec![
+            ("tag".into(), self.tag_liquid_val(slug, tag)),
             (
-                "date".into(),
-                to_liquid_val(format!("{}", post.metadata.updated.format("%Y-%m-%d"))),
-            ),
-            ("footer-nav".into(), to_liquid_val(footer_nav)),
-            (
-                "tags".into(),
-                to_liquid_val(
-                    post.metadata
-                        .tags
-                        .iter()
-                        .sorted_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()))
-                        .map(|t| self.render_tag_link(&t))
-                        .collect::<Vec<String>>()
-                        .join(", "),
-                ),
+                "page".into(),
+                liquid::value::Value::Object(liquid::value::Object::from_iter(vec![(
+                    "title".into(),
+                    to_liquid_val(self.tag_title(slug, tag)),
+                )])),
             ),
         ]);
-        let globals = liquid::value::Object::from_iter(globals_vec);
-        self.templates
-            .pages
-            .post
-            .render(&globals)
-            .expect(&format!("failed to render post: {:?}", post))
+        self.templates.render_layouts(
+            tag.name,
+            self.tag_description(slug),
+            Some(layout.into()),
+            liquid::value::Object::from_iter(globals),
+        )
     }
 
-    fn render_tag_link<S: AsRef<str>>(&self, tag: &S) -> String {
-        let globals =
-            liquid::value::Object::from_iter(vec![("tag".into(), to_liquid_val(&tag.as_ref()))]);
-        self.templates
-            .snippets
-            .tag_link
-            .render(&globals)
-            .expect(&format!("Couldn't render tag link: {}", tag.as_ref()))
+    /// The tag's config, which may be keyed by any spelling of the tag
+    fn tag_config(&self, slug: &str) -> Option<&TagConfig> {
+        self.config
+            .tags
+            .iter()
+            .find(|(name, _)| filters::slugify(name) == slug)
+            .map(|(_, config)| config)
     }
 
-    fn render_tag_for_tags_page<S: AsRef<str>>(&self, tag: &S) -> String {
-        let posts = self.tag_map.get(tag.as_ref()).expect("Tag disappeared?");
-        let post_content = posts
+    fn tag_title<'b>(&'b self, slug: &str, tag: &'b TagPosts) -> &'b str {
+        self.tag_config(slug)
+            .and_then(|c| c.title.as_deref())
+            .unwrap_or(tag.name)
+    }
+
+    /// The tag's description from the config, rendered to HTML
+    fn tag_description(&self, slug: &str) -> String {
+        self.tag_config(slug)
+            .and_then(|c| c.description.as_ref())
+            .map(|d| md_to_html(d, ContextData::get_md_opts(), self.highlighter).0)
+            .unwrap_or_default()
+    }
+
+    /// A tag as seen by templates, e.g. `{{ tag.title }}`
+    fn tag_liquid_val(&self, slug: &str, tag: &TagPosts) -> liquid::value::Value {
+        // Drafts are listed, marked as drafts, when they're being built
+        let posts = tag
+            .posts
             .iter()
-            .map(|p| p.rendered_summary.as_str())
-            .collect::<Vec<&str>>()
-            .join("\n");
-        let tag_globals = liquid::value::Object::from_iter(vec![
-            ("tag".into(), to_liquid_val(tag)),
-            ("posts".into(), to_liquid_val(post_content)),
-        ]);
-        self.templates
-            .snippets
-            .tag_posts
-            .render(&tag_globals)
-            .expect(&format!("Couldn't render tag: {}", tag.as_ref()))
+            .filter(|p| !p.metadata.unlisted)
+            .map(|p| p.liquid_val())
+            .collect();
+        let listed = tag.posts.iter().copied().filter(|p| is_listed(p));
+        let updated = last_updated(listed, self.today);
+        liquid::value::Value::Object(liquid::value::Object::from_iter(vec![
+            ("name".into(), to_liquid_val(tag.name)),
+            ("slug".into(), to_liquid_val(slug)),
+            ("url".into(), to_liquid_val(filters::tag_url(tag.name))),
+            (
+                "feed_url".into(),
+                to_liquid_val(format!("/tags/{}/atom.xml", slug)),
+            ),
+            ("title".into(), to_liquid_val(self.tag_title(slug, tag))),
+            (
+                "description".into(),
+                to_liquid_val(self.tag_description(slug)),
+            ),
+            ("posts".into(), liquid::value::Value::Array(posts)),
+            ("updated".into(), date_liquid_val(&updated)),
+            (
+                "unlisted".into(),
+                liquid::value::Value::scalar(tag.is_unlisted()),
+            ),
+        ]))
     }
 
-    fn render_tags_page_content(&self) -> String {
-        let mut tags = self.tag_map.keys().collect::<Vec<&&str>>();
-        tags.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()) );
+    /// The indices in `posts` of every part of the post's series, or
+    /// nothing if it isn't in one
+    fn series_parts(&self, post: &Post) -> &[usize] {
+        post.metadata
+            .series
+            .as_ref()
+            .and_then(|name| self.series_map.get(&filters::slugify(name)))
+            .map_or(&[], Vec::as_slice)
+    }
 
-        tags.into_iter()
-            .map(|t| self.render_tag_for_tags_page(t))
-            .collect::<Vec<String>>()
-            .join("\n")
+    /// The series as seen by templates: its `name`, the post's `part`
+    /// number out of the `total`, every part in order as `posts`, and
+    /// the `previous` and `next` parts, if there are any
+    fn series_liquid_val(&self, index: usize, post: &Post) -> Option<liquid::value::Value> {
+        let name = post.metadata.series.as_ref()?;
+        let parts = self.series_parts(post);
+        let position = parts.iter().position(|&i| i == index)?;
+        let mut series = vec![
+            ("name".into(), to_liquid_val(name)),
+            ("slug".into(), to_liquid_val(filters::slugify(name))),
+            (
+                "part".into(),
+                liquid::value::Value::scalar(position as i32 + 1),
+            ),
+            (
+                "total".into(),
+                liquid::value::Value::scalar(parts.len() as i32),
+            ),
+            (
+                "posts".into(),
+                liquid::value::Value::Array(
+                    parts.iter().map(|&i| self.post_vals[i].clone()).collect(),
+                ),
+            ),
+        ];
+        if let Some(previous) = position.checked_sub(1).map(|p| parts[p]) {
+            series.push(("previous".into(), self.post_vals[previous].clone()));
+        }
+        if let Some(&next) = parts.get(position + 1) {
+            series.push(("next".into(), self.post_vals[next].clone()));
+        }
+        Some(liquid::value::Value::Object(
+            liquid::value::Object::from_iter(series),
+        ))
     }
 
-    fn render_tags_page(&self) -> String {
-        let tags_page_content = self.render_tags_page_content();
-        self.render_generic_page("Tags", &tags_page_content)
+    /// Group posts by the slug of their series' name. Parts are ordered by
+    /// their `series_part`, with any that don't have one last, and then by
+    /// when they were created.
+    fn series_map(posts: &[Post]) -> HashMap<String, Vec<usize>> {
+        let mut series_map = HashMap::new();
+        for (i, post) in posts.iter().enumerate() {
+            if let Some(ref name) = post.metadata.series {
+                series_map
+                    .entry(filters::slugify(name))
+                    .or_insert_with(Vec::new)
+                    .push(i);
+            }
+        }
+        for parts in series_map.values_mut() {
+            parts.sort_by_key(|&i| {
+                let metadata = &posts[i].metadata;
+                (
+                    metadata.series_part.is_none(),
+                    metadata.series_part,
+                    metadata.created,
+                )
+            });
+        }
+        series_map
     }
 
-    fn tag_map<'b, T>(posts: T) -> HashMap<&'b str, Vec<&'b Post>>
+    fn tag_map<'b, T>(posts: T) -> HashMap<String, TagPosts<'b>>
     where
         T: IntoIterator<Item = &'b Post>,
     {
@@ -662,70 +966,152 @@ impl<'a> Context<'a> {
         posts.into_iter().for_each(|post| {
             post.metadata.tags.iter().for_each(|tag| {
                 tags_to_posts
-                    .entry(tag.as_str())
-                    .and_modify(|post_vec: &mut Vec<&Post>| post_vec.push(&post))
-                    .or_insert(vec![&post]);
+                    .entry(filters::slugify(tag))
+                    .or_insert_with(|| TagPosts {
+                        name: tag.as_str(),
+                        posts: Vec::new(),
+                    })
+                    .posts
+                    .push(post);
             });
         });
         tags_to_posts
     }
 }
 
-fn files_from_dir<S: AsRef<str>>(path: S) -> impl Iterator<Item = fs::DirEntry> {
-    fs::read_dir(path.as_ref())
-        .expect(&format!("Couldn't read dir: {}", path.as_ref()))
-        .map(|r| r.expect(&"couldn't read dir entry"))
+fn files_from_dir<S: AsRef<str>>(path: S) -> Result<Vec<fs::DirEntry>> {
+    let io_err = |e| SpeedyError::io(path.as_ref(), e);
+    let entries = fs::read_dir(path.as_ref())
+        .map_err(io_err)?
+        .collect::<std::io::Result<Vec<fs::DirEntry>>>()
+        .map_err(io_err)?;
+    Ok(entries
+        .into_iter()
         // exclude directories
-        .filter(|de| (&de.file_type().unwrap()).is_file())
-        // exclude zero-length file names
-        .filter(|de| &de.file_name().len() > &0)
+        .filter(|de| de.file_type().map(|t| t.is_file()).unwrap_or(false))
         // exclude any files whose names start with a dot (I'm looking at you,
         // .DS_Store!)
-        .filter(|de| &de.file_name().to_string_lossy()[0..1] != ".")
+        .filter(|de| !de.file_name().to_string_lossy().starts_with('.'))
+        .sorted_by(|a, b| a.file_name().cmp(&b.file_name()))
+        .collect())
 }
 
-fn md_to_html(md: &str, opts: MDOptions) -> String {
+/// Render markdown to HTML, returning the HTML along with every heading
+/// in it, for tables of contents
+fn md_to_html(md: &str, opts: MDOptions, highlighter: &Highlighter) -> (String, Vec<Heading>) {
     let mut html = String::new();
-    let mut heading_level: u32 = 0;
-    let parser = MDParser::new_ext(&md, opts).filter_map(|event| match event {
-        Event::Start(Tag::Heading(level @ 1..=6)) => {
-            heading_level = level;
+    let mut headings = Vec::new();
+    let mut heading_ids = HeadingIds::default();
+    // The level and contents of the heading we're in, if any
+    let mut heading: Option<(u32, Vec<Event>)> = None;
+    // The language and text of the code block we're in, if any
+    let mut code_block: Option<(Option<String>, String)> = None;
+    let parser = MDParser::new_ext(md, opts).filter_map(|event| match event {
+        Event::Start(Tag::Heading(level)) => {
+            heading = Some((level, Vec::new()));
             None
         }
-        Event::Text(text) => {
-            if heading_level != 0 {
-                let anchor = text
-                    .clone()
-                    .into_string()
-                    .trim()
-                    .to_lowercase()
-                    .replace(" ", "-");
-                let tmp = Event::Html(CowStr::from(format!(
-                    "<h{} id=\"{}\">{} <div class=\"anchor-link\" aria-hidden=\"true\"><a href=\"#{}\">⤶</a></div>",
-                    heading_level, anchor, text, anchor
-                )))
-                .into();
-                heading_level = 0;
-                return tmp;
+        Event::End(Tag::Heading(_)) => heading.take().map(|(level, mut contents)| {
+            // An explicit id, like `## Usage {#usage}`, ends the last bit
+            // of text in the heading
+            let mut custom_id = None;
+            if let Some(Event::Text(text)) = contents.last_mut() {
+                if let Some((rest, id)) = toc::split_custom_id(text) {
+                    custom_id = Some(id.to_owned());
+                    *text = rest.to_owned().into();
+                }
+            }
+            let text = contents
+                .iter()
+                .filter_map(|event| match event {
+                    Event::Text(text) | Event::Code(text) => Some(text.as_ref()),
+                    _ => None,
+                })
+                .collect::<String>();
+            let anchor = heading_ids.unique(&text, custom_id.as_deref());
+            let mut inner = String::new();
+            html::push_html(&mut inner, contents.into_iter());
+            let rendered = format!(
+                "<h{} id=\"{}\">{} <div class=\"anchor-link\" aria-hidden=\"true\"><a href=\"#{}\">⤶</a></div></h{}>\n",
+                level, anchor, inner, anchor, level
+            );
+            headings.push(Heading {
+                level,
+                id: anchor,
+                text,
+            });
+            Event::Html(rendered.into())
+        }),
+        event if heading.is_some() => {
+            if let Some((_, contents)) = heading.as_mut() {
+                contents.push(event);
             }
-            Some(Event::Text(text))
+            None
+        }
+        Event::Start(Tag::CodeBlock(kind)) => {
+            let lang = match kind {
+                CodeBlockKind::Fenced(info) => info
+                    .split_whitespace()
+                    .next()
+                    .map(|lang| lang.split(',').next().unwrap_or(lang).to_owned()),
+                CodeBlockKind::Indented => None,
+            };
+            code_block = Some((lang, String::new()));
+            None
         }
+        Event::Text(text) if code_block.is_some() => {
+            if let Some((_, code)) = code_block.as_mut() {
+                code.push_str(&text);
+            }
+            None
+        }
+        Event::End(Tag::CodeBlock(_)) => code_block.take().map(|(lang, code)| {
+            Event::Html(highlighter.code_block(lang.as_deref(), &code).into())
+        }),
         _ => Some(event),
     });
     html::push_html(&mut html, parser);
-    html
+    (html, headings)
+}
+
+/// Where a post's page is generated, relative to the output directory
+fn post_page_path(post: &Post) -> String {
+    format!("posts/{}.html", post.metadata.slug)
 }
 
-fn parse_template_str<S: AsRef<str>>(parser: &liquid::Parser, template: S) -> liquid::Template {
-    parser
-        .parse(&template.as_ref())
-        .expect(&format!("Couldn't parse template: {}", template.as_ref()))
+/// Whether a post is published and belongs in lists of posts, like the
+/// index and the feeds
+fn is_listed(post: &Post) -> bool {
+    !post.metadata.draft && !post.metadata.unlisted
+}
+
+/// When the newest of `posts` was last updated, or `today` if there are
+/// none
+fn last_updated<'a>(posts: impl Iterator<Item = &'a Post>, today: NaiveDate) -> NaiveDate {
+    posts.map(|p| p.metadata.updated).max().unwrap_or(today)
+}
+
+/// Distinguish drafts from other posts in cache hashes
+fn draft_str(post: &Post) -> &'static str {
+    if post.metadata.draft {
+        "draft"
+    } else {
+        ""
+    }
 }
 
 fn to_liquid_val<S: AsRef<str>>(string: S) -> liquid::value::Value {
     liquid::value::Value::scalar(string.as_ref().to_owned())
 }
 
+/// Dates are given to templates as `YYYY-MM-DD` strings
+fn date_liquid_val(date: &NaiveDate) -> liquid::value::Value {
+    to_liquid_val(date.format(filters::DATE_FMT).to_string())
+}
+
+fn site_liquid_val(config: &Config) -> liquid::value::Value {
+    liquid::value::to_value(&config.site).expect("couldn't convert site config")
+}
 
 // *********************************************************************
 // Add a .sorted_by method to all iterators
@@ -735,16 +1121,18 @@ fn to_liquid_val<S: AsRef<str>>(string: S) -> liquid::value::Value {
 /// As long as this trait is in scope, .sorted_by() will be available.
 trait SortedByExt: Iterator {
     fn sorted_by<F>(self, compare: F) -> IntoIter<Self::Item>
-        where
-            F: FnMut(&Self::Item, &Self::Item) -> Ordering;
+    where
+        F: FnMut(&Self::Item, &Self::Item) -> Ordering;
 }
 
 /// A blanket implementation for all iterators of .sorted_by()
-impl<I> SortedByExt for I where I: Iterator
+impl<I> SortedByExt for I
+where
+    I: Iterator,
 {
     fn sorted_by<F>(self, compare: F) -> IntoIter<Self::Item>
-        where
-            F: FnMut(&I::Item, &I::Item) -> Ordering
+    where
+        F: FnMut(&I::Item, &I::Item) -> Ordering,
     {
         let mut items: Vec<Self::Item> = self.collect();
         items.sort_by(compare);
@@ -754,31 +1142,274 @@ impl<I> SortedByExt for I where I: Iterator
 
 // *********************************************************************
 
-fn generate() {
-    let context_data = ContextData::new();
-    let context = Context::new(&context_data);
-    context.generate_all();
+/// Generate the site. In a dry run, report what would change without
+/// actually writing or deleting anything. Broken links fail the build
+/// only if `strict_links` is set and drafts aren't being built, which
+/// they often link from before they're finished; otherwise they're
+/// warnings.
+fn generate(
+    config: Config,
+    dry_run: bool,
+    strict_links: bool,
+) -> std::result::Result<(), Vec<SpeedyError>> {
+    in_pool(config.build.jobs, || {
+        generate_with(config, dry_run, strict_links)
+    })
+}
+
+/// Check the site for broken links (and anything else that would make
+/// `generate` fail) without writing anything
+fn check(config: Config) -> std::result::Result<(), Vec<SpeedyError>> {
+    in_pool(config.build.jobs, || {
+        let context_data = ContextData::new(config)?;
+        let cache = BuildCache::load(cache::CACHE_PATH, true);
+        let context = Context::new(&context_data, &cache);
+        let mut errors = context.generate_all();
+        if errors.is_empty() {
+            errors.extend(context.check_links());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    })
+}
+
+/// Run `f` on a thread pool with `jobs` threads, or one per CPU if zero
+fn in_pool<F, T>(jobs: usize, f: F) -> T
+where
+    F: FnOnce() -> T + Send,
+    T: Send,
+{
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .expect("couldn't start thread pool")
+        .install(f)
+}
+
+fn generate_with(
+    config: Config,
+    dry_run: bool,
+    strict_links: bool,
+) -> std::result::Result<(), Vec<SpeedyError>> {
+    let strict_links = strict_links && !config.build.drafts;
+    let context_data = ContextData::new(config)?;
+    let cache = BuildCache::load(cache::CACHE_PATH, dry_run);
+    let context = Context::new(&context_data, &cache);
+    let output_dir = Path::new(&context_data.config.build.output_dir);
+    let mut errors = context.generate_all();
+    // Anything that failed to generate would look stale, so only clean
+    // up after a successful build
+    let mut complete = errors.is_empty();
+    if complete {
+        if let Err(e) = cache.remove_stale(output_dir) {
+            errors.push(e);
+            complete = false;
+        }
+        let (broken, mut others): (Vec<_>, Vec<_>) = context
+            .check_links()
+            .into_iter()
+            .partition(|e| matches!(e, SpeedyError::BrokenLink { .. }));
+        if strict_links {
+            errors.extend(broken);
+        } else {
+            error::warn(&broken);
+        }
+        errors.append(&mut others);
+    }
+    cache.report();
+    if let Err(e) = cache.save(output_dir, complete) {
+        errors.push(e);
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Load the config, applying any overrides from the command line
+fn load_config(opts: &ArgMatches) -> Result<Config> {
+    let path = opts
+        .value_of("config")
+        .unwrap_or(config::DEFAULT_CONFIG_PATH);
+    let mut config = Config::load(path)?;
+    if opts.is_present("drafts") {
+        config.build.drafts = true;
+    }
+    if let Some(jobs) = opts.value_of("jobs") {
+        config.build.jobs = jobs.parse().expect("validated by clap");
+    }
+    if let Some(output) = opts.value_of("output") {
+        config.build.output_dir = output.into();
+    }
+    config.check_output_dir()?;
+    Ok(config)
+}
+
+fn load_config_or_exit(opts: &ArgMatches) -> Config {
+    load_config(opts).unwrap_or_else(|e| {
+        error::report(&[e]);
+        process::exit(1);
+    })
+}
+
+/// Generate the site, reporting any errors. Returns whether it succeeded.
+/// See `generate` for `strict_links`.
+fn generate_and_report(opts: &ArgMatches, strict_links: bool) -> bool {
+    let dry_run = opts.is_present("dry-run");
+    let result = load_config(opts)
+        .map_err(|e| vec![e])
+        .and_then(|config| generate(config, dry_run, strict_links));
+    if let Err(errors) = result {
+        error::report(&errors);
+        return false;
+    }
+    true
+}
+
+/// Check the site, exiting with an error if anything is wrong
+fn check_or_exit(opts: &ArgMatches) {
+    let config = load_config_or_exit(opts);
+    match check(config) {
+        Ok(()) => println!("no problems found"),
+        Err(errors) => {
+            error::report(&errors);
+            process::exit(1);
+        }
+    }
+}
+
+/// Generate the site and exit with an error if anything went wrong
+fn generate_or_exit(opts: &ArgMatches, strict_links: bool) {
+    if !generate_and_report(opts, strict_links) {
+        process::exit(1);
+    }
+}
+
+/// Regenerate the site whenever its sources change, reloading any open
+/// pages afterward
+fn run_watch(opts: &ArgMatches<'static>) {
+    let config = load_config_or_exit(opts);
+    let mut paths = vec![
+        PathBuf::from(&config.build.posts_dir),
+        PathBuf::from(&config.build.templates_dir),
+        PathBuf::from(&config.build.assets_dir),
+        config.path.clone(),
+    ];
+    if config.build.drafts {
+        paths.push(PathBuf::from(&config.build.drafts_dir));
+    }
+    if let Some(ref theme_dir) = config.build.theme_dir {
+        paths.push(PathBuf::from(theme_dir));
+    }
+
+    generate_and_report(opts, false);
+    let reloader = serve::Reloader::default();
+    let watch_opts = opts.clone();
+    let watch_reloader = reloader.clone();
+    let watching = serve::watch(&paths, move || {
+        println!("Change detected, regenerating");
+        if generate_and_report(&watch_opts, false) {
+            watch_reloader.reload();
+        }
+    });
+    if let Err(e) = watching {
+        error::report(&[e]);
+        process::exit(1);
+    }
+    serve::run_with_reload(config.build.output_dir, reloader);
 }
 
-fn run() {
-    let index = warp::fs::dir("static");
-    println!("Serving on http://127.0.0.1:5000/");
-    warp::serve(index).run(([127, 0, 0, 1], 5000));
+/// Generate the site and serve it
+fn run(opts: &ArgMatches) {
+    let config = load_config_or_exit(opts);
+    generate_or_exit(opts, false);
+    serve::run(config.build.output_dir);
 }
 
-fn cli<'a>() -> ArgMatches<'a> {
+fn cli() -> ArgMatches<'static> {
     App::new("speedy")
-        .subcommand(SubCommand::with_name("generate"))
-        .subcommand(SubCommand::with_name("run"))
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .takes_value(true)
+                .default_value(config::DEFAULT_CONFIG_PATH)
+                .global(true)
+                .help("Path to the site configuration file"),
+        )
+        .subcommand(
+            SubCommand::with_name("generate")
+                .arg(drafts_arg())
+                .arg(jobs_arg())
+                .arg(output_arg())
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("List what would be written or deleted without changing anything"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .arg(drafts_arg())
+                .arg(jobs_arg())
+                .arg(output_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("run")
+                .arg(drafts_arg())
+                .arg(jobs_arg())
+                .arg(output_arg())
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .short("w")
+                        .help("Regenerate the site and reload open pages when sources change"),
+                ),
+        )
         .get_matches()
 }
 
+fn drafts_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("drafts")
+        .long("drafts")
+        .help("Include draft posts")
+}
+
+fn jobs_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("jobs")
+        .long("jobs")
+        .short("j")
+        .takes_value(true)
+        .value_name("N")
+        .validator(|n| {
+            n.parse::<usize>()
+                .map(|_| ())
+                .map_err(|_| format!("expected a number of threads, found {:?}", n))
+        })
+        .help("Number of threads to render with (default: one per CPU)")
+}
+
+fn output_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("output")
+        .long("output")
+        .short("o")
+        .takes_value(true)
+        .value_name("DIR")
+        .help("Directory to write the site to")
+}
+
 fn main() {
     let opts = cli();
-    match opts.subcommand_name() {
-        Some("run") => run(),
-        Some("generate") => generate(),
-        Some(_) => println!("??"),
-        None => run(),
+    match opts.subcommand() {
+        ("run", Some(sub_opts)) if sub_opts.is_present("watch") => run_watch(sub_opts),
+        ("run", Some(sub_opts)) => run(sub_opts),
+        ("generate", Some(sub_opts)) => generate_or_exit(sub_opts, true),
+        ("check", Some(sub_opts)) => check_or_exit(sub_opts),
+        (_, Some(_)) => println!("??"),
+        (_, None) => run(&opts),
     }
 }
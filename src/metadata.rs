@@ -0,0 +1,638 @@
+use std::path::Path;
+
+use chrono::NaiveDate;
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer};
+
+use crate::error::{strip_location, Location, Result, SpeedyError};
+use crate::filters;
+
+pub const YAML_DELIMITER: &str = "---";
+const TOML_DELIMITER: &str = "+++";
+const LIST_DELIMITER: &str = ",";
+/// The keys a legacy header may have. Legacy headers end at the first
+/// line that isn't one of these, so that a body starting with something
+/// like `Note: ...` isn't mistaken for a header.
+const LEGACY_KEYS: &[&str] = &[
+    "title",
+    "slug",
+    "created",
+    "updated",
+    "tags",
+    "summary",
+    "draft",
+    "unlisted",
+    "noindex",
+    "toc",
+    "series",
+    "series_part",
+    "aliases",
+];
+const DATE_FMT: &str = "%Y-%m-%d";
+
+/// Post metadata, read from the front matter at the top of each post.
+///
+/// Front matter may be YAML delimited by `---` lines, TOML delimited by
+/// `+++` lines, or the legacy format of bare `key: value` lines ending
+/// at the first line that isn't a header.
+#[derive(Debug)]
+pub struct Metadata {
+    pub title: String,
+    pub slug: String,
+    pub created: NaiveDate,
+    pub updated: NaiveDate,
+    pub tags: Vec<String>,
+    pub summary: String,
+    /// Drafts are left out of builds unless specifically requested
+    pub draft: bool,
+    /// Unlisted posts are published, but left out of every list of posts:
+    /// the index, tag pages, feeds, the sitemap, and search
+    pub unlisted: bool,
+    /// Asks search engines not to index the post, and leaves it out of
+    /// the sitemap
+    pub noindex: bool,
+    /// Whether to show a table of contents, if not the site's default
+    pub toc: Option<bool>,
+    /// The name of the series the post is part of, if any
+    pub series: Option<String>,
+    /// Where the post falls in its series. Parts without one come after
+    /// those with one, in order of creation.
+    pub series_part: Option<u32>,
+    /// Other paths the post used to be at, relative to the output
+    /// directory, e.g. `posts/old-slug.html`
+    pub aliases: Vec<String>,
+    /// Any keys we don't know about, kept around for templates
+    pub extra: liquid::value::Object,
+}
+impl Metadata {
+    /// Split a post into its metadata and its markdown body.
+    ///
+    /// The post's file stem is used as its slug if the front matter
+    /// doesn't specify one.
+    pub fn from_post<'a>(path: &Path, text: &'a str) -> Result<(Self, &'a str)> {
+        let error = |location, message| SpeedyError::FrontMatter {
+            path: path.to_owned(),
+            location,
+            message,
+        };
+        // Fields are deserialized from a buffer, so errors about their
+        // values don't say where they are. Ours name the key, though, so
+        // look for that instead.
+        let invalid = |header: &str, header_offset, location: Option<Location>, message: String| {
+            match field_error(&message) {
+                Some((key, message)) => error(
+                    key_location(header, key)
+                        .map(|l| l.offset_lines(header_offset))
+                        .or(location),
+                    message,
+                ),
+                None => error(location, message),
+            }
+        };
+        let unterminated = |delimiter| {
+            error(
+                Some(Location::new(1, 1)),
+                format!("no closing {:?} for front matter", delimiter),
+            )
+        };
+
+        // Delimited front matter starts on the line after the delimiter,
+        // so locations within it are offset by one line
+        let (header, header_offset, front_matter, body) = match text.lines().next().map(str::trim) {
+            Some(YAML_DELIMITER) => {
+                let (header, body) = split_delimited(text, YAML_DELIMITER)
+                    .ok_or_else(|| unterminated(YAML_DELIMITER))?;
+                let front_matter = serde_yaml::from_str::<FrontMatter>(header).map_err(|e| {
+                    invalid(
+                        header,
+                        1,
+                        yaml_location(&e).map(|l| l.offset_lines(1)),
+                        strip_location(&e),
+                    )
+                })?;
+                (header, 1, front_matter, body)
+            }
+            Some(TOML_DELIMITER) => {
+                let (header, body) = split_delimited(text, TOML_DELIMITER)
+                    .ok_or_else(|| unterminated(TOML_DELIMITER))?;
+                let front_matter = toml::from_str::<toml::Value>(header)
+                    .map(stringify_toml_dates)
+                    .and_then(|v| v.try_into::<FrontMatter>())
+                    .map_err(|e| {
+                        invalid(
+                            header,
+                            1,
+                            Location::from_toml(&e).map(|l| l.offset_lines(1)),
+                            strip_location(&e),
+                        )
+                    })?;
+                (header, 1, front_matter, body)
+            }
+            _ => {
+                let (header, body) = split_legacy(text);
+                let front_matter = serde_yaml::from_str::<FrontMatter>(&legacy_to_yaml(header))
+                    .map_err(|e| invalid(header, 0, yaml_location(&e), strip_location(&e)))?;
+                (header, 0, front_matter, body)
+            }
+        };
+        let date = |key: &str, value: &str| {
+            NaiveDate::parse_from_str(value.trim(), DATE_FMT).map_err(|e| {
+                error(
+                    key_location(header, key).map(|l| l.offset_lines(header_offset)),
+                    format!("invalid {} date {:?}: {}", key, value, e),
+                )
+            })
+        };
+
+        let aliases = front_matter
+            .aliases
+            .iter()
+            .map(|alias| {
+                alias_path(alias).ok_or_else(|| {
+                    error(
+                        key_location(header, "aliases").map(|l| l.offset_lines(header_offset)),
+                        format!(
+                            "invalid alias {:?}: expected an old slug or a path on the site",
+                            alias
+                        ),
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // A tag's page is named after its slug, so a tag needs one
+        if let Some(tag) = front_matter
+            .tags
+            .iter()
+            .find(|tag| filters::slugify(tag).is_empty())
+        {
+            return Err(error(
+                key_location(header, "tags").map(|l| l.offset_lines(header_offset)),
+                format!("tag {:?} needs at least one letter or number", tag),
+            ));
+        }
+
+        let created = date("created", &front_matter.created)?;
+        let updated = match front_matter.updated {
+            Some(ref updated) => date("updated", updated)?,
+            None => created,
+        };
+        let default_slug = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let metadata = Self {
+            title: front_matter.title,
+            slug: front_matter.slug.unwrap_or(default_slug),
+            created,
+            updated,
+            tags: front_matter.tags,
+            summary: front_matter.summary,
+            draft: front_matter.draft,
+            unlisted: front_matter.unlisted,
+            noindex: front_matter.noindex,
+            toc: front_matter.toc,
+            series: front_matter.series.filter(|s| !s.trim().is_empty()),
+            series_part: front_matter.series_part,
+            aliases,
+            extra: front_matter.extra,
+        };
+        Ok((metadata, body))
+    }
+}
+
+/// The deserialized form of the front matter, prior to filling in defaults
+#[derive(Debug, Deserialize)]
+struct FrontMatter {
+    title: String,
+    slug: Option<String>,
+    created: String,
+    updated: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_tags")]
+    tags: Vec<String>,
+    #[serde(default)]
+    summary: String,
+    #[serde(default, deserialize_with = "deserialize_draft")]
+    draft: bool,
+    #[serde(default, deserialize_with = "deserialize_unlisted")]
+    unlisted: bool,
+    #[serde(default, deserialize_with = "deserialize_noindex")]
+    noindex: bool,
+    #[serde(default, deserialize_with = "deserialize_toc")]
+    toc: Option<bool>,
+    series: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_series_part")]
+    series_part: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_aliases")]
+    aliases: Vec<String>,
+    #[serde(flatten)]
+    extra: liquid::value::Object,
+}
+
+/// Split YAML front matter delimited by `---` lines from the rest of `text`
+pub fn split_yaml(text: &str) -> Option<(&str, &str)> {
+    match text.lines().next().map(str::trim) {
+        Some(YAML_DELIMITER) => split_delimited(text, YAML_DELIMITER),
+        _ => None,
+    }
+}
+
+/// Split text whose first line is `delimiter` at the next `delimiter` line
+fn split_delimited<'a>(text: &'a str, delimiter: &str) -> Option<(&'a str, &'a str)> {
+    let start = text.find('\n').map_or(text.len(), |i| i + 1);
+    let mut offset = start;
+    for line in text[start..].split_inclusive('\n') {
+        if line.trim() == delimiter {
+            return Some((&text[start..offset], &text[offset + line.len()..]));
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Split off the leading `key: value` lines of a legacy post, which end at
+/// the first blank line or the first line that isn't a known header
+fn split_legacy(text: &str) -> (&str, &str) {
+    let header_len = text
+        .split_inclusive('\n')
+        .take_while(|line| legacy_header(line).is_some())
+        .map(str::len)
+        .sum();
+    text.split_at(header_len)
+}
+
+/// Convert legacy headers to YAML, treating every value as a string and
+/// keeping each header on its original line so error locations line up
+fn legacy_to_yaml(header: &str) -> String {
+    header
+        .lines()
+        .filter_map(legacy_header)
+        .map(|(key, value)| format!("{}: '{}'\n", key, value.replace('\'', "''")))
+        .collect()
+}
+
+fn legacy_header(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.splitn(2, ':');
+    let key = parts.next()?.trim();
+    let value = parts.next()?.trim();
+    if LEGACY_KEYS.contains(&key) {
+        Some((key, value))
+    } else {
+        None
+    }
+}
+
+/// The file an alias redirects from, relative to the output directory. A
+/// bare slug is an old post slug, and anything else is a path on the site,
+/// with paths to directories redirecting from their `index.html`.
+fn alias_path(alias: &str) -> Option<String> {
+    if alias.contains("://") || alias.contains(['?', '#', ' ']) {
+        return None;
+    }
+    if !alias.contains('/') && !alias.contains('.') {
+        return Some(format!("posts/{}.html", alias));
+    }
+    let parts = alias
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+    if parts.iter().any(|part| *part == "." || *part == "..") {
+        return None;
+    }
+    let path = parts.join("/");
+    Some(if path.is_empty() {
+        "index.html".to_owned()
+    } else if alias.ends_with('/') {
+        format!("{}/index.html", path)
+    } else {
+        path
+    })
+}
+
+/// TOML has a native date type, but we want to treat dates the same
+/// regardless of front matter format, so turn them back into strings.
+fn stringify_toml_dates(value: toml::Value) -> toml::Value {
+    match value {
+        toml::Value::Datetime(date) => toml::Value::String(date.to_string()),
+        toml::Value::Array(values) => {
+            toml::Value::Array(values.into_iter().map(stringify_toml_dates).collect())
+        }
+        toml::Value::Table(table) => toml::Value::Table(
+            table
+                .into_iter()
+                .map(|(k, v)| (k, stringify_toml_dates(v)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+/// Find where the value for `key` starts within the front matter
+fn key_location(header: &str, key: &str) -> Option<Location> {
+    header.lines().enumerate().find_map(|(i, line)| {
+        let rest = line.trim_start().strip_prefix(key)?.trim_start();
+        let value = rest.strip_prefix(':').or_else(|| rest.strip_prefix('='))?;
+        let column = line.len() - value.trim_start().len() + 1;
+        Some(Location::new(i + 1, column))
+    })
+}
+
+pub fn yaml_location(error: &serde_yaml::Error) -> Option<Location> {
+    error
+        .location()
+        .map(|l| Location::new(l.line(), l.column()))
+}
+
+/// The key named at the start of an error from one of our field
+/// deserializers, e.g. "`draft` must be true or false", along with the
+/// message without anything TOML added about the key
+fn field_error(message: &str) -> Option<(&str, String)> {
+    let rest = message.strip_prefix('`')?;
+    let key = &rest[..rest.find('`')?];
+    let message = match message.rfind(" for key `") {
+        Some(i) => &message[..i],
+        None => message,
+    };
+    Some((key, message.to_owned()))
+}
+
+/// Legacy headers are always strings, so also accept "true" or "false"
+fn deserialize_bool<'de, D>(deserializer: D, key: &str) -> std::result::Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Bool(bool),
+        String(String),
+        Other(IgnoredAny),
+    }
+    let invalid = |found: String| {
+        serde::de::Error::custom(format!("`{}` must be true or false, found {}", key, found))
+    };
+    match Value::deserialize(deserializer)? {
+        Value::Bool(b) => Ok(b),
+        Value::String(s) => s.trim().parse().map_err(|_| invalid(format!("{:?}", s))),
+        Value::Other(_) => Err(invalid("something else".to_owned())),
+    }
+}
+
+fn deserialize_draft<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_bool(deserializer, "draft")
+}
+
+fn deserialize_unlisted<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_bool(deserializer, "unlisted")
+}
+
+fn deserialize_noindex<'de, D>(deserializer: D) -> std::result::Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_bool(deserializer, "noindex")
+}
+
+fn deserialize_toc<'de, D>(deserializer: D) -> std::result::Result<Option<bool>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_bool(deserializer, "toc").map(Some)
+}
+
+/// Legacy headers are always strings, so also accept numbers written as
+/// strings
+fn deserialize_series_part<'de, D>(deserializer: D) -> std::result::Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Number(u32),
+        String(String),
+        Other(IgnoredAny),
+    }
+    let invalid = |found: String| {
+        serde::de::Error::custom(format!(
+            "`series_part` must be a whole number, found {}",
+            found
+        ))
+    };
+    match Value::deserialize(deserializer)? {
+        Value::Number(n) => Ok(Some(n)),
+        Value::String(s) => s
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid(format!("{:?}", s))),
+        Value::Other(_) => Err(invalid("something else".to_owned())),
+    }
+}
+
+/// Tags and aliases may be given either as a list or as a comma-delimited
+/// string. Numbers and booleans in a list (e.g. `[rust, 2020]`) are taken
+/// as strings.
+fn deserialize_list<'de, D>(
+    deserializer: D,
+    key: &str,
+) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Item {
+        String(String),
+        Bool(bool),
+        Integer(i64),
+        Float(f64),
+    }
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Delimited(String),
+        List(Vec<Item>),
+        Other(IgnoredAny),
+    }
+    let items = match Value::deserialize(deserializer)? {
+        Value::Delimited(items) => items
+            .split(LIST_DELIMITER)
+            .map(|s| s.trim().to_owned())
+            .collect::<Vec<String>>(),
+        Value::List(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Item::String(s) => s.trim().to_owned(),
+                Item::Bool(b) => b.to_string(),
+                Item::Integer(n) => n.to_string(),
+                Item::Float(n) => n.to_string(),
+            })
+            .collect(),
+        Value::Other(_) => {
+            return Err(serde::de::Error::custom(format!(
+                "`{}` must be a list of strings or a comma-delimited string",
+                key
+            )))
+        }
+    };
+    Ok(items.into_iter().filter(|i| !i.is_empty()).collect())
+}
+
+fn deserialize_tags<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_list(deserializer, "tags")
+}
+
+fn deserialize_aliases<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_list(deserializer, "aliases")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<(Metadata, &str)> {
+        Metadata::from_post(Path::new("posts/why-blog.md"), text)
+    }
+
+    fn error_at(text: &str) -> (Option<Location>, String) {
+        match parse(text) {
+            Err(SpeedyError::FrontMatter {
+                location, message, ..
+            }) => (location, message),
+            other => panic!("expected a front matter error, got {:?}", other),
+        }
+    }
+
+    fn date(text: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(text, DATE_FMT).unwrap()
+    }
+
+    #[test]
+    fn yaml() {
+        let (metadata, body) = parse(
+            "---\ntitle: Why Blog\nslug: why\ncreated: 2019-06-01\nupdated: 2019-06-02\n\
+             tags: [rust, 2020, true]\ndraft: true\nauthor: Someone\n---\n# Why\n",
+        )
+        .unwrap();
+        assert_eq!(metadata.title, "Why Blog");
+        assert_eq!(metadata.slug, "why");
+        assert_eq!(metadata.created, date("2019-06-01"));
+        assert_eq!(metadata.updated, date("2019-06-02"));
+        assert_eq!(metadata.tags, vec!["rust", "2020", "true"]);
+        assert!(metadata.draft);
+        assert!(metadata.extra.contains_key("author"));
+        assert_eq!(body, "# Why\n");
+    }
+
+    #[test]
+    fn toml() {
+        let (metadata, body) = parse(
+            "+++\ntitle = \"Why Blog\"\ncreated = 2019-06-01\ntags = \"rust, blog\"\n\
+             series_part = 2\n+++\n# Why\n",
+        )
+        .unwrap();
+        assert_eq!(metadata.title, "Why Blog");
+        assert_eq!(metadata.created, date("2019-06-01"));
+        assert_eq!(metadata.tags, vec!["rust", "blog"]);
+        assert_eq!(metadata.series_part, Some(2));
+        assert_eq!(body, "# Why\n");
+    }
+
+    #[test]
+    fn legacy() {
+        let (metadata, body) = parse(
+            "title: Why Blog: A Question\ncreated: 2019-06-01\ntags: rust, blog\n\
+             draft: false\nunlisted: true\nnoindex: true\nseries_part: 3\n\n# Why\n",
+        )
+        .unwrap();
+        assert_eq!(metadata.title, "Why Blog: A Question");
+        assert_eq!(metadata.tags, vec!["rust", "blog"]);
+        assert!(!metadata.draft);
+        assert!(metadata.unlisted);
+        assert!(metadata.noindex);
+        assert_eq!(metadata.series_part, Some(3));
+        assert_eq!(body, "\n# Why\n");
+    }
+
+    #[test]
+    fn legacy_header_ends_at_unknown_key() {
+        let (metadata, body) =
+            parse("title: Why Blog\ncreated: 2019-06-01\nNote: this is the body\n").unwrap();
+        assert!(metadata.extra.is_empty());
+        assert_eq!(body, "Note: this is the body\n");
+    }
+
+    #[test]
+    fn defaults() {
+        let (metadata, _) = parse("---\ntitle: Why Blog\ncreated: 2019-06-01\n---\n").unwrap();
+        assert_eq!(metadata.slug, "why-blog");
+        assert_eq!(metadata.updated, metadata.created);
+        assert!(metadata.tags.is_empty());
+        assert!(!metadata.draft);
+        assert!(!metadata.unlisted);
+        assert!(!metadata.noindex);
+        assert_eq!(metadata.toc, None);
+    }
+
+    #[test]
+    fn missing_field() {
+        let (_, message) = error_at("---\ntitle: Why Blog\n---\n");
+        assert!(message.contains("missing field `created`"), "{}", message);
+        let (_, message) = error_at("+++\ncreated = 2019-06-01\n+++\n");
+        assert!(message.contains("missing field `title`"), "{}", message);
+    }
+
+    #[test]
+    fn unterminated() {
+        let (location, message) = error_at("---\ntitle: Why Blog\n");
+        assert_eq!(location, Some(Location::new(1, 1)));
+        assert!(message.contains("no closing"), "{}", message);
+    }
+
+    #[test]
+    fn invalid_date_location() {
+        let (location, _) = error_at("---\ntitle: Why Blog\ncreated: June\n---\n");
+        assert_eq!(location, Some(Location::new(3, 10)));
+        let (location, _) = error_at("title: Why Blog\ncreated: June\n\nbody");
+        assert_eq!(location, Some(Location::new(2, 10)));
+    }
+
+    #[test]
+    fn invalid_field_location() {
+        let (location, message) =
+            error_at("---\ntitle: Why Blog\ncreated: 2019-06-01\ntags: {a: 1}\n---\n");
+        assert_eq!(location, Some(Location::new(4, 7)));
+        assert!(message.starts_with("`tags`"), "{}", message);
+        let (location, message) =
+            error_at("+++\ntitle = \"Why Blog\"\ncreated = 2019-06-01\ndraft = \"maybe\"\n+++\n");
+        assert_eq!(location, Some(Location::new(4, 9)));
+        assert_eq!(message, "`draft` must be true or false, found \"maybe\"");
+    }
+
+    #[test]
+    fn tag_without_slug() {
+        let (location, message) =
+            error_at("title: Why Blog\ncreated: 2019-06-01\ntags: rust, ??\n\nbody");
+        assert_eq!(location, Some(Location::new(3, 7)));
+        assert_eq!(message, "tag \"??\" needs at least one letter or number");
+    }
+
+    #[test]
+    fn yaml_syntax_error_location() {
+        let (location, _) = error_at("---\ntitle: Why Blog\ncreated: [2019\n---\n");
+        assert_eq!(location.map(|l| l.line), Some(4));
+    }
+}
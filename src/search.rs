@@ -0,0 +1,126 @@
+//! A full-text search index of the site's posts, which the search page
+//! queries in the browser so that searching needs no server
+
+use std::collections::HashSet;
+
+use pulldown_cmark::{Event, Options, Parser, Tag};
+use serde::Serialize;
+
+use crate::toc;
+
+/// Words too common to be worth searching for, which are left out of the
+/// index and ignored in searches
+const STOP_WORDS: &[&str] = &[
+    "a", "about", "after", "all", "also", "an", "and", "any", "are", "as", "at", "be", "because",
+    "been", "but", "by", "can", "could", "did", "do", "does", "for", "from", "had", "has", "have",
+    "he", "her", "his", "how", "i", "if", "in", "into", "is", "it", "its", "just", "me", "more",
+    "my", "no", "not", "of", "on", "one", "or", "our", "out", "she", "so", "some", "than", "that",
+    "the", "their", "them", "then", "there", "these", "they", "this", "to", "up", "us", "was",
+    "we", "were", "what", "when", "which", "who", "will", "with", "would", "you", "your",
+];
+
+/// A post as it appears in the search index
+#[derive(Serialize)]
+pub struct Entry<'a> {
+    pub title: &'a str,
+    /// Relative to the site's root, so that results work wherever the
+    /// site is served from
+    pub url: String,
+    /// Plain text, since results are shown without any markup
+    pub summary: String,
+    pub tags: &'a [String],
+    pub created: String,
+    /// The post's distinct words, from `words`
+    pub text: String,
+}
+
+/// The index, along with the stop words, so that the search page ignores
+/// the same ones
+#[derive(Serialize)]
+struct Index<'a> {
+    stop_words: &'a [&'a str],
+    posts: &'a [Entry<'a>],
+}
+
+/// The words of a post's markdown `body`, without any markup. Code blocks
+/// are left out to keep the index small, but inline code is kept.
+pub fn plain_text(body: &str, opts: Options) -> String {
+    let mut text = String::new();
+    let mut in_code_block = false;
+    let mut heading_start = None;
+    for event in Parser::new_ext(body, opts) {
+        match event {
+            Event::Start(Tag::CodeBlock(_)) => in_code_block = true,
+            Event::End(Tag::CodeBlock(_)) => in_code_block = false,
+            Event::Start(Tag::Heading(_)) => heading_start = Some(text.len()),
+            Event::End(Tag::Heading(_)) => {
+                // Drop any explicit `{#id}`, which isn't part of the text
+                if let Some(start) = heading_start.take() {
+                    let len = toc::split_custom_id(&text[start..]).map(|(rest, _)| rest.len());
+                    if let Some(len) = len {
+                        text.truncate(start + len);
+                    }
+                }
+                text.push(' ');
+            }
+            Event::Text(t) | Event::Code(t) if !in_code_block => text.push_str(&t),
+            // Inline markup ends mid-sentence, so it doesn't separate words
+            Event::End(Tag::Emphasis)
+            | Event::End(Tag::Strong)
+            | Event::End(Tag::Strikethrough)
+            | Event::End(Tag::Link(..))
+            | Event::End(Tag::Image(..)) => (),
+            Event::SoftBreak | Event::HardBreak | Event::End(_) => text.push(' '),
+            _ => (),
+        }
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Every distinct word in `text` that isn't a stop word, lowercased and
+/// in order of first appearance. The search page only looks for words,
+/// so this finds the same posts as the full text in a fraction of the
+/// space.
+pub fn words(text: &str) -> String {
+    let mut seen = STOP_WORDS
+        .iter()
+        .map(|w| w.to_string())
+        .collect::<HashSet<_>>();
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .map(str::to_lowercase)
+        .filter(|word| !word.is_empty() && seen.insert(word.clone()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The index as compact JSON
+pub fn index(entries: &[Entry]) -> String {
+    let index = Index {
+        stop_words: STOP_WORDS,
+        posts: entries,
+    };
+    serde_json::to_string(&index).expect("search entries are always valid JSON")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_leaves_out_markup_and_code_blocks() {
+        let body = "# Intro {#start}\n\nSome *very* `fast` [code](/x).\n\n\
+                    ```rust\nfn main() {}\n```\n\n- one\n- two\n";
+        assert_eq!(
+            plain_text(body, Options::empty()),
+            "Intro Some very fast code. one two"
+        );
+    }
+
+    #[test]
+    fn words_are_distinct_and_skip_stop_words() {
+        assert_eq!(
+            words("The Rust book, the rust_lang site, and Rust's café."),
+            "rust book rust_lang site s café"
+        );
+    }
+}
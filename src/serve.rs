@@ -0,0 +1,183 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::mpsc::{unbounded, UnboundedSender};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use warp::http::Uri;
+use warp::path::{FullPath, Tail};
+use warp::{Filter, Rejection, Stream};
+
+use crate::error::{Result, SpeedyError};
+use crate::REDIRECTS_PATH;
+
+const ADDRESS: ([u8; 4], u16) = ([127, 0, 0, 1], 5000);
+/// How long to wait for changes to settle before reporting them
+const DEBOUNCE: Duration = Duration::from_millis(200);
+/// Path of the server-sent events endpoint that tells pages to reload.
+/// This must match the URL in `RELOAD_SCRIPT`.
+const RELOAD_PATH: &str = "_speedy-reload";
+const RELOAD_SCRIPT: &str = r#"<script>
+new EventSource("/_speedy-reload").onmessage = function () { location.reload(); };
+</script>
+"#;
+
+/// Serve the generated site
+pub fn run<P: Into<PathBuf>>(dir: P) {
+    let dir = dir.into();
+    println!("Serving on http://127.0.0.1:5000/");
+    warp::serve(redirects(dir.clone()).or(warp::fs::dir(dir))).run(ADDRESS);
+}
+
+/// Serve the generated site, injecting a script into every page that
+/// reloads it whenever `reloader` says so
+pub fn run_with_reload<P: Into<PathBuf>>(dir: P, reloader: Reloader) {
+    let dir = dir.into();
+    let events = warp::path(RELOAD_PATH)
+        .and(warp::path::end())
+        .and(warp::sse())
+        .map(move |sse: warp::sse::Sse| {
+            let reloads = reloader
+                .subscribe()
+                .map(|()| warp::sse::data("reload"))
+                .map_err(|()| io::Error::other("reloader dropped"));
+            sse.reply(warp::sse::keep_alive().stream(reloads))
+        });
+    let html_dir = dir.clone();
+    let pages = warp::get2().and(warp::path::tail()).and_then(
+        move |tail: Tail| -> std::result::Result<_, Rejection> {
+            let path = html_path(&html_dir, tail.as_str()).ok_or_else(warp::reject::not_found)?;
+            let html = fs::read_to_string(path).map_err(|_| warp::reject::not_found())?;
+            Ok(warp::reply::html(inject_reload_script(html)))
+        },
+    );
+    println!("Serving on http://127.0.0.1:5000/ with live reload");
+    warp::serve(
+        events
+            .or(redirects(dir.clone()))
+            .or(pages)
+            .or(warp::fs::dir(dir)),
+    )
+    .run(ADDRESS);
+}
+
+/// Answer requests for the paths in the generated redirects manifest with
+/// a permanent redirect. The manifest is read for every request, so that
+/// it's always up to date when regenerating on changes.
+fn redirects(
+    dir: PathBuf,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = Rejection> + Clone {
+    warp::get2().and(warp::path::full()).and_then(
+        move |path: FullPath| -> std::result::Result<_, Rejection> {
+            let target = redirect_target(&dir, path.as_str())
+                .and_then(|target| target.parse::<Uri>().ok())
+                .ok_or_else(warp::reject::not_found)?;
+            Ok(warp::redirect(target))
+        },
+    )
+}
+
+/// Where the redirects manifest in `dir` says a request path should go,
+/// if anywhere
+fn redirect_target(dir: &Path, request_path: &str) -> Option<String> {
+    let manifest = fs::read_to_string(dir.join(REDIRECTS_PATH)).ok()?;
+    manifest.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(from), Some(to)) if from == request_path => Some(to.to_owned()),
+            _ => None,
+        }
+    })
+}
+
+/// Notifies every open page that it should reload
+#[derive(Clone, Default)]
+pub struct Reloader {
+    clients: Arc<Mutex<Vec<UnboundedSender<()>>>>,
+}
+impl Reloader {
+    fn subscribe(&self) -> futures::sync::mpsc::UnboundedReceiver<()> {
+        let (tx, rx) = unbounded();
+        self.clients.lock().expect("poisoned lock").push(tx);
+        rx
+    }
+
+    pub fn reload(&self) {
+        // Pages that have been closed or reloaded drop their receivers,
+        // so forget about any client we can no longer reach
+        self.clients
+            .lock()
+            .expect("poisoned lock")
+            .retain(|client| client.unbounded_send(()).is_ok());
+    }
+}
+
+/// Watch `paths` recursively in a background thread, calling `on_change`
+/// whenever something in them changes. Paths that don't exist are ignored.
+pub fn watch<F>(paths: &[PathBuf], mut on_change: F) -> Result<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, DEBOUNCE).map_err(|e| SpeedyError::Watch {
+        path: PathBuf::from("."),
+        message: e.to_string(),
+    })?;
+    for path in paths.iter().filter(|p| p.exists()) {
+        watcher
+            .watch(path, RecursiveMode::Recursive)
+            .map_err(|e| SpeedyError::Watch {
+                path: path.clone(),
+                message: e.to_string(),
+            })?;
+    }
+    thread::spawn(move || {
+        // The watcher stops when dropped, so keep it around for as long
+        // as we're receiving its events
+        let _watcher = watcher;
+        for event in rx {
+            match event {
+                DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => (),
+                DebouncedEvent::Error(e, path) => eprintln!(
+                    "{}\n",
+                    SpeedyError::Watch {
+                        path: path.unwrap_or_default(),
+                        message: e.to_string(),
+                    }
+                ),
+                _ => on_change(),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Map a request path onto an HTML file in `dir`, if it names one
+fn html_path(dir: &Path, request_path: &str) -> Option<PathBuf> {
+    let mut path = dir.to_owned();
+    for component in Path::new(request_path).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            _ => return None,
+        }
+    }
+    if request_path.is_empty() || request_path.ends_with('/') {
+        path.push("index.html");
+    }
+    match path.extension() {
+        Some(ext) if ext == "html" => Some(path),
+        _ => None,
+    }
+}
+
+fn inject_reload_script(mut html: String) -> String {
+    match html.rfind("</body>") {
+        Some(i) => html.insert_str(i, RELOAD_SCRIPT),
+        None => html.push_str(RELOAD_SCRIPT),
+    }
+    html
+}
@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use liquid::partials::{EagerCompiler, InMemorySource};
+use liquid::value::{Object, Value};
+
+use crate::cache;
+use crate::config::Config;
+use crate::error::{strip_location, Location, Result, SpeedyError};
+use crate::filters;
+use crate::metadata;
+
+/// Includes may be used from any template as `{% include '<name>' %}`,
+/// where the name is the file name without its extension
+const INCLUDE_DIRS: &[&str] = &["blocks", "snippets"];
+/// Layouts wrap pages, and may themselves name a layout to be wrapped in
+const LAYOUTS_DIR: &str = "layouts";
+/// Each page is rendered to the same path in the output directory
+const PAGES_DIR: &str = "pages";
+
+/// A template's default source, built into the binary so that a site
+/// can be generated without any templates of its own
+#[derive(Clone, Copy)]
+struct TemplateString {
+    /// Path relative to the templates directory
+    path: &'static str,
+    source: &'static str,
+}
+
+/// Embed the template at the given path relative to `templates/`
+macro_rules! template_string {
+    ($path:literal) => {
+        TemplateString {
+            path: $path,
+            source: include_str!(concat!("../templates/", $path)),
+        }
+    };
+}
+
+const DEFAULT_TEMPLATES: &[TemplateString] = &[
+    template_string!("blocks/about.html"),
+    template_string!("blocks/header.html"),
+    template_string!("blocks/notfound.html"),
+    template_string!("layouts/base.html"),
+    template_string!("layouts/post.html"),
+    template_string!("layouts/redirect.html"),
+    template_string!("layouts/tag-atom.xml"),
+    template_string!("layouts/tag.html"),
+    template_string!("pages/about.html"),
+    template_string!("pages/atom.xml"),
+    template_string!("pages/feed.json"),
+    template_string!("pages/index.html"),
+    template_string!("pages/notfound.html"),
+    template_string!("pages/posts.html"),
+    template_string!("pages/robots.txt"),
+    template_string!("pages/rss.xml"),
+    template_string!("pages/search.html"),
+    template_string!("pages/sitemap.xml"),
+    template_string!("pages/tags.html"),
+    template_string!("snippets/atom-entry.xml"),
+    template_string!("snippets/atom-feed.xml"),
+    template_string!("snippets/footer-common.html"),
+    template_string!("snippets/footer-nav.html"),
+    template_string!("snippets/head.html"),
+    template_string!("snippets/index-content.html"),
+    template_string!("snippets/posts-content.html"),
+    template_string!("snippets/posts-post.html"),
+    template_string!("snippets/series.html"),
+    template_string!("snippets/tag-link.html"),
+    template_string!("snippets/toc.html"),
+];
+
+/// A parsed template, along with the name used to refer to it in errors
+pub struct Template {
+    pub name: String,
+    template: liquid::Template,
+}
+impl Template {
+    pub fn render(&self, globals: &Object) -> Result<String> {
+        self.template
+            .render(globals)
+            .map_err(|e| SpeedyError::template_render(&self.name, e))
+    }
+}
+
+/// A page or layout template, which may start with YAML front matter
+pub struct Page {
+    pub template: Template,
+    /// Available in templates as `page`, e.g. `{{ page.title }}`
+    pub front_matter: Object,
+}
+impl Page {
+    fn layout(&self) -> Option<String> {
+        self.front_matter
+            .get("layout")
+            .map(|layout| layout.to_str().into_owned())
+    }
+}
+
+pub struct Templates {
+    /// Keyed by path relative to the pages directory
+    pub pages: BTreeMap<String, Page>,
+    /// Keyed by name, e.g. `base` for `layouts/base.html`
+    layouts: HashMap<String, Page>,
+    /// The name and hash of every template
+    pub hashes: Vec<(String, String)>,
+}
+impl Templates {
+    /// Load and parse all templates, collecting any errors into `errors`
+    pub fn load(config: &Config, errors: &mut Vec<SpeedyError>) -> Self {
+        let mut loader = TemplateLoader::new(config);
+        let includes = INCLUDE_DIRS
+            .iter()
+            .flat_map(|dir| loader.load_dir(dir))
+            .collect::<Vec<TemplateSource>>();
+        let layouts = loader.load_dir(LAYOUTS_DIR);
+        let pages = loader.load_dir(PAGES_DIR);
+        errors.append(&mut loader.errors);
+
+        // Parse includes on their own first, so that errors in them are
+        // reported against the right file
+        let url_base = &config.site.url_base;
+        let plain_parser = filters::add_filters(liquid::ParserBuilder::with_liquid(), url_base)
+            .build()
+            .expect("failed to build parser");
+        let mut partials = EagerCompiler::<InMemorySource>::empty();
+        for include in &includes {
+            let source = match plain_parser.parse(&include.source) {
+                Ok(_) => include.source.as_str(),
+                Err(e) => {
+                    errors.push(SpeedyError::template_parse(&include.name, e));
+                    ""
+                }
+            };
+            partials.add(include.stem(), source);
+        }
+        let parser = filters::add_filters(liquid::ParserBuilder::with_liquid(), url_base)
+            .partials(partials)
+            .build()
+            .expect("includes were already parsed");
+
+        Self {
+            layouts: layouts
+                .iter()
+                .map(|layout| (layout.stem(), parse_page(&parser, layout, errors)))
+                .collect(),
+            pages: pages
+                .iter()
+                .map(|page| {
+                    let path = page.path[PAGES_DIR.len() + 1..].to_owned();
+                    (path, parse_page(&parser, page, errors))
+                })
+                .collect(),
+            hashes: loader.hashes,
+        }
+    }
+
+    /// Render a page, along with any layout it's wrapped in
+    pub fn render_page(&self, page: &Page, globals: &Object) -> Result<String> {
+        let mut globals = globals.clone();
+        globals.insert("page".into(), Value::Object(page.front_matter.clone()));
+        let content = page.template.render(&globals)?;
+        self.render_layouts(&page.template.name, content, page.layout(), globals)
+    }
+
+    /// Wrap `content` in the given layout, then in that layout's layout,
+    /// and so on. Each layout's front matter is added to `page`, without
+    /// replacing anything already set by the page or an inner layout.
+    pub fn render_layouts(
+        &self,
+        name: &str,
+        mut content: String,
+        layout: Option<String>,
+        mut globals: Object,
+    ) -> Result<String> {
+        let error = |message| SpeedyError::TemplateRender {
+            name: name.to_owned(),
+            message,
+        };
+        let mut seen = Vec::new();
+        let mut next = layout;
+        while let Some(layout_name) = next {
+            if seen.contains(&layout_name) {
+                return Err(error(format!("layout {:?} wraps itself", layout_name)));
+            }
+            let layout = self
+                .layouts
+                .get(&layout_name)
+                .ok_or_else(|| error(format!("unknown layout {:?}", layout_name)))?;
+            let mut page = match globals.remove("page") {
+                Some(Value::Object(page)) => page,
+                _ => Object::new(),
+            };
+            for (key, value) in layout.front_matter.iter() {
+                if !page.contains_key(key) {
+                    page.insert(key.clone(), value.clone());
+                }
+            }
+            globals.insert("page".into(), Value::Object(page));
+            globals.insert("content".into(), Value::scalar(content));
+            content = layout.template.render(&globals)?;
+            next = layout.layout();
+            seen.push(layout_name);
+        }
+        Ok(content)
+    }
+}
+
+/// Template source found at runtime
+struct TemplateSource {
+    /// Path relative to the templates directory, e.g. `pages/index.html`
+    path: String,
+    /// Name used to refer to the template in errors
+    name: String,
+    source: String,
+}
+impl TemplateSource {
+    /// The file name without its extension, e.g. `index`
+    fn stem(&self) -> String {
+        Path::new(&self.path)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+}
+
+/// Finds templates at runtime.
+///
+/// Templates are looked for in the theme directory (if there is one),
+/// then the templates directory, falling back to the defaults built into
+/// the binary.
+struct TemplateLoader {
+    /// In order of increasing precedence
+    dirs: Vec<PathBuf>,
+    errors: Vec<SpeedyError>,
+    /// The name and hash of every template loaded so far
+    hashes: Vec<(String, String)>,
+}
+impl TemplateLoader {
+    fn new(config: &Config) -> Self {
+        let dirs = std::iter::once(&config.build.templates_dir)
+            .chain(config.build.theme_dir.iter())
+            .map(PathBuf::from)
+            .collect();
+        Self {
+            dirs,
+            errors: Vec::new(),
+            hashes: Vec::new(),
+        }
+    }
+
+    /// Load every template within `subdir` of the templates directories
+    fn load_dir(&mut self, subdir: &str) -> Vec<TemplateSource> {
+        let mut found = BTreeMap::new();
+        let prefix = format!("{}/", subdir);
+        for default in DEFAULT_TEMPLATES
+            .iter()
+            .filter(|t| t.path.starts_with(&prefix))
+        {
+            let template = TemplateSource {
+                path: default.path.to_owned(),
+                name: format!("<built-in>/{}", default.path),
+                source: default.source.to_owned(),
+            };
+            found.insert(template.path.clone(), template);
+        }
+        for dir in &self.dirs {
+            let files = match cache::files_in_tree(&dir.join(subdir)) {
+                Ok(files) => files,
+                Err(e) => {
+                    self.errors.push(e);
+                    continue;
+                }
+            };
+            for file in files {
+                let is_hidden = file
+                    .file_name()
+                    .is_none_or(|name| name.to_string_lossy().starts_with('.'));
+                if is_hidden {
+                    continue;
+                }
+                let path = file
+                    .strip_prefix(dir)
+                    .expect("file outside templates dir")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                match fs::read_to_string(&file) {
+                    Ok(source) => {
+                        let template = TemplateSource {
+                            path: path.clone(),
+                            name: file.display().to_string(),
+                            source,
+                        };
+                        found.insert(path, template);
+                    }
+                    Err(e) => self.errors.push(SpeedyError::io(&file, e)),
+                }
+            }
+        }
+        let templates = found.into_values().collect::<Vec<_>>();
+        self.hashes.extend(
+            templates
+                .iter()
+                .map(|t| (t.name.clone(), cache::hash(&t.source))),
+        );
+        templates
+    }
+}
+
+/// Parse a template, recording any error in `errors`. An empty template
+/// is returned on failure so that we can keep looking for more errors.
+///
+/// `line_offset` is the number of lines preceding `body` in the file.
+fn parse_template(
+    parser: &liquid::Parser,
+    name: &str,
+    body: &str,
+    line_offset: usize,
+    errors: &mut Vec<SpeedyError>,
+) -> Template {
+    let parsed = parser.parse(body).unwrap_or_else(|e| {
+        errors.push(SpeedyError::template_parse(name, e).offset_lines(line_offset));
+        parser.parse("").expect("empty templates always parse")
+    });
+    Template {
+        name: name.to_owned(),
+        template: parsed,
+    }
+}
+
+/// Parse a page or layout, along with its front matter
+fn parse_page(
+    parser: &liquid::Parser,
+    template: &TemplateSource,
+    errors: &mut Vec<SpeedyError>,
+) -> Page {
+    let name = &template.name;
+    let error = |location, message| SpeedyError::FrontMatter {
+        path: PathBuf::from(name),
+        location,
+        message,
+    };
+    let (front_matter, body, header_lines) = match metadata::split_yaml(&template.source) {
+        Some((header, body)) => {
+            let parsed = if header.trim().is_empty() {
+                Ok(Object::new())
+            } else {
+                serde_yaml::from_str::<Object>(header)
+            };
+            let front_matter = parsed.unwrap_or_else(|e| {
+                errors.push(error(
+                    metadata::yaml_location(&e).map(|l| l.offset_lines(1)),
+                    strip_location(&e),
+                ));
+                Object::new()
+            });
+            // the delimiters take up a line each
+            (front_matter, body, header.lines().count() + 2)
+        }
+        None => {
+            if template.source.starts_with(metadata::YAML_DELIMITER) {
+                errors.push(error(
+                    Some(Location::new(1, 1)),
+                    format!("no closing {:?} for front matter", metadata::YAML_DELIMITER),
+                ));
+            }
+            (Object::new(), template.source.as_str(), 0)
+        }
+    };
+    Page {
+        template: parse_template(parser, name, body, header_lines, errors),
+        front_matter,
+    }
+}
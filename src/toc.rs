@@ -0,0 +1,118 @@
+//! Tables of contents, built from the headings in a post
+
+use std::collections::HashSet;
+use std::iter::FromIterator;
+
+use liquid::value::{Object, Value};
+
+use crate::filters;
+
+/// A heading found while rendering a post
+#[derive(Debug)]
+pub struct Heading {
+    /// 1 for `<h1>`, 2 for `<h2>`, etc.
+    pub level: u32,
+    pub id: String,
+    /// The heading without any markup
+    pub text: String,
+}
+
+/// Hands out ids for the headings on a page, so that no two are the same
+#[derive(Default)]
+pub struct HeadingIds {
+    used: HashSet<String>,
+}
+impl HeadingIds {
+    /// The `custom` id if there is one, and otherwise the heading's text,
+    /// slugified. An id already used on the page gets a number appended,
+    /// e.g. `usage-1`.
+    pub fn unique(&mut self, text: &str, custom: Option<&str>) -> String {
+        let base = match custom {
+            Some(custom) => custom.to_owned(),
+            None => match filters::slugify(text) {
+                slug if slug.is_empty() => "section".to_owned(),
+                slug => slug,
+            },
+        };
+        let mut id = base.clone();
+        let mut suffix = 0;
+        while self.used.contains(&id) {
+            suffix += 1;
+            id = format!("{}-{}", base, suffix);
+        }
+        self.used.insert(id.clone());
+        id
+    }
+}
+
+/// Split an explicit id like `{#usage}` off the end of a heading's text,
+/// returning the rest of the text and the id
+pub fn split_custom_id(text: &str) -> Option<(&str, &str)> {
+    let rest = text.trim_end().strip_suffix('}')?;
+    let start = rest.rfind("{#")?;
+    let id = &rest[start + 2..];
+    let is_valid = !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_alphanumeric() || "-_.:".contains(c));
+    if is_valid {
+        Some((rest[..start].trim_end(), id))
+    } else {
+        None
+    }
+}
+
+/// A heading in a table of contents, along with the headings nested
+/// beneath it
+#[derive(Debug)]
+pub struct TocEntry {
+    level: u32,
+    id: String,
+    text: String,
+    children: Vec<TocEntry>,
+}
+impl TocEntry {
+    /// The entry as seen by templates, e.g. `{{ entry.text }}`
+    fn liquid_val(&self) -> Value {
+        Value::Object(Object::from_iter(vec![
+            ("level".into(), Value::scalar(self.level as i32)),
+            ("id".into(), Value::scalar(self.id.clone())),
+            ("url".into(), Value::scalar(format!("#{}", self.id))),
+            ("text".into(), Value::scalar(self.text.clone())),
+            ("children".into(), liquid_val(&self.children)),
+        ]))
+    }
+}
+
+/// Nest the headings between `min_depth` and `max_depth` (inclusive)
+/// beneath the nearest preceding heading of a higher level. A skipped
+/// level (e.g. an `<h4>` straight after an `<h2>`) is nested directly
+/// beneath the higher heading.
+pub fn build(headings: &[Heading], min_depth: u32, max_depth: u32) -> Vec<TocEntry> {
+    let included = headings
+        .iter()
+        .filter(|h| h.level >= min_depth && h.level <= max_depth)
+        .collect::<Vec<_>>();
+    nest(&included, &mut 0, 0)
+}
+
+/// Collect the headings from `next` onward that are below `parent_level`
+fn nest(headings: &[&Heading], next: &mut usize, parent_level: u32) -> Vec<TocEntry> {
+    let mut entries = Vec::new();
+    while let Some(heading) = headings.get(*next).filter(|h| h.level > parent_level) {
+        *next += 1;
+        entries.push(TocEntry {
+            level: heading.level,
+            id: heading.id.clone(),
+            text: heading.text.clone(),
+            children: nest(headings, next, heading.level),
+        });
+    }
+    entries
+}
+
+/// A table of contents as seen by templates: an array of entries, each
+/// with its `level`, `id`, `url`, `text`, and `children`
+pub fn liquid_val(entries: &[TocEntry]) -> Value {
+    Value::Array(entries.iter().map(TocEntry::liquid_val).collect())
+}
@@ -0,0 +1,111 @@
+// Searches the posts in /search.json, which is generated along with the
+// site, for the words in the search box. Posts must contain every word,
+// and are ranked by where the words appear: titles count most, then tags,
+// then summaries, then the rest of the post. Words too common to be in
+// the index are ignored.
+(function () {
+    "use strict";
+
+    var WEIGHTS = { title: 10, tags: 5, summary: 3, text: 1 };
+    var MAX_RESULTS = 50;
+
+    var query = document.getElementById("search-query");
+    var status = document.getElementById("search-status");
+    var results = document.getElementById("search-results");
+    var index = null;
+    var stopWords = {};
+
+    function words(text) {
+        return text.toLowerCase().split(/[^\p{L}\p{N}_]+/u).filter(function (word) {
+            return word && !stopWords.hasOwnProperty(word);
+        });
+    }
+
+    function prepare(posts) {
+        return posts.map(function (post) {
+            return {
+                post: post,
+                fields: {
+                    title: post.title.toLowerCase(),
+                    tags: post.tags.join(" ").toLowerCase(),
+                    summary: post.summary.toLowerCase(),
+                    text: post.text.toLowerCase()
+                }
+            };
+        });
+    }
+
+    function score(entry, terms) {
+        var total = 0;
+        for (var i = 0; i < terms.length; i++) {
+            var termScore = 0;
+            for (var field in WEIGHTS) {
+                if (entry.fields[field].indexOf(terms[i]) !== -1) {
+                    termScore += WEIGHTS[field];
+                }
+            }
+            if (termScore === 0) {
+                return 0;
+            }
+            total += termScore;
+        }
+        return total;
+    }
+
+    function render(matches, text) {
+        results.textContent = "";
+        matches.slice(0, MAX_RESULTS).forEach(function (match) {
+            var post = match.entry.post;
+            var item = document.createElement("li");
+            var link = document.createElement("a");
+            link.href = post.url;
+            link.textContent = post.title;
+            var date = document.createElement("span");
+            date.className = "post-date";
+            date.textContent = " " + post.created;
+            var summary = document.createElement("p");
+            summary.textContent = post.summary;
+            item.append(link, date, summary);
+            results.appendChild(item);
+        });
+        status.textContent = matches.length === 0
+            ? "No posts found for “" + text + "”."
+            : matches.length + " post" + (matches.length === 1 ? "" : "s") +
+              " found for “" + text + "”.";
+    }
+
+    function search() {
+        var text = query.value.trim();
+        var terms = words(text);
+        if (terms.length === 0) {
+            results.textContent = "";
+            status.textContent = "";
+            return;
+        }
+        var matches = index
+            .map(function (entry) { return { entry: entry, score: score(entry, terms) }; })
+            .filter(function (match) { return match.score > 0; })
+            .sort(function (a, b) { return b.score - a.score; });
+        render(matches, text);
+    }
+
+    query.value = new URLSearchParams(location.search).get("q") || "";
+    status.textContent = "Loading…";
+    fetch("/search.json")
+        .then(function (response) {
+            if (!response.ok) {
+                throw new Error(response.statusText);
+            }
+            return response.json();
+        })
+        .then(function (data) {
+            data.stop_words.forEach(function (word) { stopWords[word] = true; });
+            index = prepare(data.posts);
+            status.textContent = "";
+            query.addEventListener("input", search);
+            search();
+        })
+        .catch(function (error) {
+            status.textContent = "Couldn't load the search index: " + error.message;
+        });
+})();